@@ -0,0 +1,343 @@
+// Audio output
+//
+// The game produces samples on the main thread, SDL pulls them on its audio
+// thread. AudioRing is a single producer/single consumer ring between the
+// two: each side only ever advances its own cursor, so no lock is needed.
+// The cursors count frames (one left and one right sample) since startup
+// and only get wrapped when indexing into the data.
+//
+// AudioLatency decides every game frame how far ahead of the play position
+// the game writes. It starts at the configured latency, backs off by a
+// device chunk whenever the ring ran dry and slowly gives back surplus when
+// the ring never came close to running dry.
+//
+// Without a sound device RealtimeSink takes the place of the SDL callback,
+// it drains the ring on its own thread at the rate a device would and
+// optionally writes what it took into a WAV file.
+
+use libc::{c_void, mmap, MAP_PRIVATE, MAP_ANON, MAP_FAILED, PROT_READ, PROT_WRITE};
+use std::cmp;
+use std::mem;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::usize;
+
+use wav::WavFile;
+
+pub const CHANNELS: usize = 2;
+
+// How long the ring has to go without an underrun before the latency is
+// lowered again
+const ADJUST_SECONDS: u32 = 2;
+
+pub struct AudioRing {
+    data: *mut i16,
+    capacity: usize,
+    read_index: AtomicUsize,
+    write_index: AtomicUsize,
+
+    // Consumer side bookkeeping for the latency measurement
+    last_read_counter: AtomicU64,
+    last_read_frames: AtomicUsize,
+    min_queued: AtomicUsize,
+    underruns: AtomicUsize,
+}
+
+// data is only touched through the cursor protocol below, each half of it
+// by exactly one side at a time
+unsafe impl Send for AudioRing {}
+unsafe impl Sync for AudioRing {}
+
+impl AudioRing {
+    // capacity is in frames
+    pub fn new(capacity: usize) -> AudioRing {
+        let data = unsafe {
+            // Allocation implicitly freed at the end of the execution
+            mmap(ptr::null_mut(),
+                 capacity * CHANNELS * mem::size_of::<i16>(),
+                 PROT_READ | PROT_WRITE,
+                 MAP_PRIVATE | MAP_ANON,
+                 -1,
+                 0)
+        };
+        if data.is_null() || data == MAP_FAILED {
+            panic!("Audio buffer could not be optained!");
+        }
+
+        AudioRing {
+            data: data as *mut i16,
+            capacity: capacity,
+            read_index: AtomicUsize::new(0),
+            write_index: AtomicUsize::new(0),
+            last_read_counter: AtomicU64::new(0),
+            last_read_frames: AtomicUsize::new(0),
+            min_queued: AtomicUsize::new(usize::MAX),
+            underruns: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn as_user_data(&self) -> *mut c_void {
+        self as *const AudioRing as *mut c_void
+    }
+
+    pub fn queued_frames(&self) -> usize {
+        let read = self.read_index.load(Ordering::Acquire);
+        let write = self.write_index.load(Ordering::Acquire);
+        write.wrapping_sub(read)
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.capacity - self.queued_frames()
+    }
+
+    // Producer side, returns how many frames fit into the ring
+    pub fn write(&self, samples: &[i16]) -> usize {
+        let write = self.write_index.load(Ordering::Relaxed);
+        let read = self.read_index.load(Ordering::Acquire);
+        let free = self.capacity - write.wrapping_sub(read);
+        let frames = cmp::min(free, samples.len() / CHANNELS);
+
+        let start = write % self.capacity;
+        let first = cmp::min(frames, self.capacity - start);
+        unsafe {
+            ptr::copy_nonoverlapping(samples.as_ptr(),
+                                     self.data.offset((start * CHANNELS) as isize),
+                                     first * CHANNELS);
+            ptr::copy_nonoverlapping(samples.as_ptr().offset((first * CHANNELS) as isize),
+                                     self.data,
+                                     (frames - first) * CHANNELS);
+        }
+
+        self.write_index.store(write.wrapping_add(frames), Ordering::Release);
+        frames
+    }
+
+    // Consumer side, called on the audio thread with the performance counter
+    // of the callback. Whatever the game did not deliver in time is played
+    // as silence.
+    pub fn read(&self, out: &mut [i16], counter: u64) {
+        let read = self.read_index.load(Ordering::Relaxed);
+        let write = self.write_index.load(Ordering::Acquire);
+        let queued = write.wrapping_sub(read);
+        let wanted = out.len() / CHANNELS;
+        let frames = cmp::min(queued, wanted);
+
+        let start = read % self.capacity;
+        let first = cmp::min(frames, self.capacity - start);
+        unsafe {
+            ptr::copy_nonoverlapping(self.data.offset((start * CHANNELS) as isize),
+                                     out.as_mut_ptr(),
+                                     first * CHANNELS);
+            ptr::copy_nonoverlapping(self.data,
+                                     out.as_mut_ptr().offset((first * CHANNELS) as isize),
+                                     (frames - first) * CHANNELS);
+        }
+        for sample in &mut out[frames * CHANNELS..] {
+            *sample = 0;
+        }
+
+        self.read_index.store(read.wrapping_add(frames), Ordering::Release);
+
+        // The device starts pulling before the game wrote anything, the fill
+        // level only counts once the ring got primed by the first write
+        if write != 0 {
+            if frames < wanted {
+                self.underruns.fetch_add(1, Ordering::Relaxed);
+            }
+            // The producer only ever resets this, losing a minimum to that
+            // race just delays the next adjustment
+            if queued < self.min_queued.load(Ordering::Relaxed) {
+                self.min_queued.store(queued, Ordering::Relaxed);
+            }
+        }
+        self.last_read_frames.store(wanted, Ordering::Relaxed);
+        self.last_read_counter.store(counter, Ordering::Release);
+    }
+
+    pub fn underruns(&self) -> usize {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    // Smallest fill level seen by the consumer since the last call
+    fn take_min_queued(&self) -> usize {
+        self.min_queued.swap(usize::MAX, Ordering::Relaxed)
+    }
+
+    // Counter and size of the chunk the device took last
+    fn last_read(&self) -> (u64, usize) {
+        let counter = self.last_read_counter.load(Ordering::Acquire);
+        (counter, self.last_read_frames.load(Ordering::Relaxed))
+    }
+}
+
+pub struct AudioLatency {
+    samples_per_second: u32,
+    frames_per_update: usize,
+    min_target: usize,
+    max_target: usize,
+    target: usize,
+    underruns_seen: usize,
+    stable_updates: u32,
+    updates_per_adjust: u32,
+}
+
+impl AudioLatency {
+    pub fn new(samples_per_second: u32,
+               game_refresh_rate: u32,
+               device_frames: usize,
+               initial_ms: u32,
+               ring: &AudioRing)
+               -> AudioLatency {
+        let frames_per_update = (samples_per_second / game_refresh_rate) as usize;
+        // At least one game frame plus the chunk the device is playing, the
+        // ring has to keep one game frame free to accept the next write
+        let min_target = frames_per_update + device_frames;
+        let max_target = cmp::max(min_target, ring.capacity - frames_per_update);
+        let initial = (samples_per_second as u64 * initial_ms as u64 / 1000) as usize;
+
+        AudioLatency {
+            samples_per_second: samples_per_second,
+            frames_per_update: frames_per_update,
+            min_target: min_target,
+            max_target: max_target,
+            target: cmp::max(min_target, cmp::min(max_target, initial)),
+            underruns_seen: ring.underruns(),
+            stable_updates: 0,
+            updates_per_adjust: game_refresh_rate * ADJUST_SECONDS,
+        }
+    }
+
+    pub fn target_ms(&self) -> f32 {
+        self.target as f32 * 1000.0 / self.samples_per_second as f32
+    }
+
+    // Frames between what the game writes now and what the listener hears
+    // now: everything still queued plus the part of the last chunk the device
+    // has not played yet, estimated from the time since its callback.
+    pub fn measured_frames(&self, ring: &AudioRing, counter: u64, frequency: u64) -> usize {
+        let (read_counter, read_frames) = ring.last_read();
+        let queued = ring.queued_frames();
+        if read_counter == 0 || counter < read_counter {
+            return queued;
+        }
+
+        let played = ((counter - read_counter) as f64 / frequency as f64 *
+                      self.samples_per_second as f64) as usize;
+        queued + read_frames.saturating_sub(played)
+    }
+
+    // Call once per game frame, returns how many frames the game has to
+    // produce to reach the current target
+    pub fn frames_to_write(&mut self, ring: &AudioRing, counter: u64, frequency: u64) -> usize {
+        self.adapt(ring);
+
+        let measured = self.measured_frames(ring, counter, frequency);
+        cmp::min(self.target.saturating_sub(measured), ring.free_frames())
+    }
+
+    fn adapt(&mut self, ring: &AudioRing) {
+        let underruns = ring.underruns();
+        if underruns != self.underruns_seen {
+            self.underruns_seen = underruns;
+            self.stable_updates = 0;
+            ring.take_min_queued();
+
+            let (_, chunk) = ring.last_read();
+            let step = cmp::max(chunk, self.frames_per_update / 2);
+            let target = cmp::min(self.max_target, self.target + step);
+            if target != self.target {
+                self.target = target;
+                println!("Audio underrun, writing {:.1}ms ahead now", self.target_ms());
+            }
+        } else {
+            self.stable_updates += 1;
+            if self.stable_updates >= self.updates_per_adjust {
+                self.stable_updates = 0;
+
+                // The ring never got below min_queued, everything above one
+                // device chunk of that was lag without any benefit
+                let min_queued = ring.take_min_queued();
+                let (_, chunk) = ring.last_read();
+                if min_queued != usize::MAX && min_queued > chunk {
+                    let surplus = (min_queued - chunk) / 2;
+                    let target = cmp::max(self.min_target, self.target.saturating_sub(surplus));
+                    if target != self.target {
+                        self.target = target;
+                        println!("Audio stable, writing {:.1}ms ahead now", self.target_ms());
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct RealtimeSink {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RealtimeSink {
+    // counter has to be the same clock the main loop measures latency with
+    pub fn start(ring: Arc<AudioRing>,
+                 samples_per_second: u32,
+                 chunk_frames: usize,
+                 counter: fn() -> u64,
+                 mut file: Option<WavFile>)
+                 -> RealtimeSink {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+
+        let thread = thread::spawn(move || {
+            let chunk_duration = Duration::from_nanos(chunk_frames as u64 * 1_000_000_000 /
+                                                      samples_per_second as u64);
+            let mut chunk = vec![0i16; chunk_frames * CHANNELS];
+            let mut next_chunk = Instant::now();
+
+            while thread_running.load(Ordering::Relaxed) {
+                ring.read(&mut chunk, counter());
+
+                let mut file_failed = false;
+                if let Some(ref mut wav_file) = file {
+                    if let Err(error) = wav_file.write_samples(&chunk) {
+                        println!("Writing the audio output file failed, stopping it! ({})",
+                                 error);
+                        file_failed = true;
+                    }
+                }
+                if file_failed {
+                    file = None;
+                }
+
+                // Sleep on an absolute schedule so the rate doesn't drift
+                next_chunk += chunk_duration;
+                let now = Instant::now();
+                if next_chunk > now {
+                    thread::sleep(next_chunk - now);
+                } else {
+                    next_chunk = now;
+                }
+            }
+
+            if let Some(wav_file) = file {
+                if let Err(error) = wav_file.finish() {
+                    println!("The audio output file could not be finished! ({})", error);
+                }
+            }
+        });
+
+        RealtimeSink {
+            running: running,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn stop(mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,273 @@
+// Key and gamepad bindings
+//
+// bindings.cfg next to the executable maps physical keys and controller
+// buttons onto the logical buttons of a ControllerInput. One binding per
+// line, several sources may be bound to the same logical button:
+//
+//     # comment
+//     move_up = key:W
+//     move_up = key:Up
+//     action_down = pad:a
+//
+// Keys are physical positions, so the bindings work the same on any layout.
+// They use the SDL scancode names, which name the key at that position on a
+// US layout ("W", "Space", "Left Shift", ...). Pad buttons use the SDL game
+// controller names ("a", "dpup", "leftshoulder", "start", ...).
+// If the file contains no key bindings (or does not exist) the default keys
+// are used, the same goes for the pad bindings.
+//
+// The platform handles RESERVED_KEYS itself before the bindings get a look,
+// binding one of them prints a warning.
+
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use ffi::sdl::*;
+use common::{Button, ControllerInput};
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum LogicalButton {
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    ActionUp,
+    ActionDown,
+    ActionLeft,
+    ActionRight,
+    LeftShoulder,
+    RightShoulder,
+    Start,
+    Back,
+}
+
+impl LogicalButton {
+    fn from_name(name: &str) -> Option<LogicalButton> {
+        match name {
+            "move_up" => Some(LogicalButton::MoveUp),
+            "move_down" => Some(LogicalButton::MoveDown),
+            "move_left" => Some(LogicalButton::MoveLeft),
+            "move_right" => Some(LogicalButton::MoveRight),
+            "action_up" => Some(LogicalButton::ActionUp),
+            "action_down" => Some(LogicalButton::ActionDown),
+            "action_left" => Some(LogicalButton::ActionLeft),
+            "action_right" => Some(LogicalButton::ActionRight),
+            "left_shoulder" => Some(LogicalButton::LeftShoulder),
+            "right_shoulder" => Some(LogicalButton::RightShoulder),
+            "start" => Some(LogicalButton::Start),
+            "back" => Some(LogicalButton::Back),
+            _ => None,
+        }
+    }
+
+    pub fn button<'a>(&self, controller: &'a ControllerInput) -> &'a Button {
+        match *self {
+            LogicalButton::MoveUp => &controller.move_up,
+            LogicalButton::MoveDown => &controller.move_down,
+            LogicalButton::MoveLeft => &controller.move_left,
+            LogicalButton::MoveRight => &controller.move_right,
+            LogicalButton::ActionUp => &controller.action_up,
+            LogicalButton::ActionDown => &controller.action_down,
+            LogicalButton::ActionLeft => &controller.action_left,
+            LogicalButton::ActionRight => &controller.action_right,
+            LogicalButton::LeftShoulder => &controller.left_shoulder,
+            LogicalButton::RightShoulder => &controller.right_shoulder,
+            LogicalButton::Start => &controller.start,
+            LogicalButton::Back => &controller.back,
+        }
+    }
+
+    pub fn button_mut<'a>(&self, controller: &'a mut ControllerInput) -> &'a mut Button {
+        match *self {
+            LogicalButton::MoveUp => &mut controller.move_up,
+            LogicalButton::MoveDown => &mut controller.move_down,
+            LogicalButton::MoveLeft => &mut controller.move_left,
+            LogicalButton::MoveRight => &mut controller.move_right,
+            LogicalButton::ActionUp => &mut controller.action_up,
+            LogicalButton::ActionDown => &mut controller.action_down,
+            LogicalButton::ActionLeft => &mut controller.action_left,
+            LogicalButton::ActionRight => &mut controller.action_right,
+            LogicalButton::LeftShoulder => &mut controller.left_shoulder,
+            LogicalButton::RightShoulder => &mut controller.right_shoulder,
+            LogicalButton::Start => &mut controller.start,
+            LogicalButton::Back => &mut controller.back,
+        }
+    }
+}
+
+pub const RESERVED_KEYS: [(SDL_Scancode, &'static str); 4] =
+    [(SDL_SCANCODE_L, "L cycles the input recording"),
+     (SDL_SCANCODE_F12, "F12 captures a frame"),
+     (SDL_SCANCODE_F4, "Alt+F4 quits"),
+     (SDL_SCANCODE_RETURN, "Alt+Enter toggles fullscreen")];
+
+pub struct Bindings {
+    path: PathBuf,
+    write_time: Option<SystemTime>,
+    keys: Vec<(SDL_Scancode, LogicalButton)>,
+    pad_buttons: Vec<(SDL_GameControllerButton, LogicalButton)>,
+}
+
+impl Bindings {
+    pub fn load(path: PathBuf) -> Bindings {
+        let mut result = Bindings {
+            path: path,
+            write_time: None,
+            keys: Vec::new(),
+            pad_buttons: Vec::new(),
+        };
+        result.reload();
+        result
+    }
+
+    // Call once per frame, rereads the file when it was changed. Returns true
+    // after a reload, buttons held down until then may never see their
+    // release through the new bindings.
+    pub fn reload_if_changed(&mut self) -> bool {
+        if modified_time(&self.path) != self.write_time {
+            self.reload();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn for_each_bound_key<F>(&self, scancode: SDL_Scancode, mut f: F)
+        where F: FnMut(LogicalButton)
+    {
+        for &(key, logical) in &self.keys {
+            if key == scancode {
+                f(logical);
+            }
+        }
+    }
+
+    pub fn is_pad_button_down(&self,
+                              logical: LogicalButton,
+                              controller: *mut SDL_GameController)
+                              -> bool {
+        self.pad_buttons
+            .iter()
+            .filter(|&&(_, bound)| bound == logical)
+            .any(|&(button, _)| unsafe { SDL_GameControllerGetButton(controller, button) != 0 })
+    }
+
+    fn reload(&mut self) {
+        self.write_time = modified_time(&self.path);
+        self.keys.clear();
+        self.pad_buttons.clear();
+
+        if let Ok(file) = File::open(&self.path) {
+            for (line_number, line) in BufReader::new(file).lines().enumerate() {
+                match line {
+                    Ok(line) => self.parse_line(&line, line_number + 1),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        if self.keys.is_empty() {
+            self.keys = default_keys();
+        }
+        if self.pad_buttons.is_empty() {
+            self.pad_buttons = default_pad_buttons();
+        }
+    }
+
+    fn parse_line(&mut self, line: &str, line_number: usize) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let logical = parts.next().map(str::trim).and_then(LogicalButton::from_name);
+        let source = parts.next().map(str::trim).unwrap_or("");
+
+        let logical = match logical {
+            Some(logical) => logical,
+            None => {
+                println!("{:?}:{}: unknown button", self.path, line_number);
+                return;
+            }
+        };
+
+        let name = match CString::new(source.get(4..).unwrap_or("")) {
+            Ok(name) => name,
+            Err(_) => {
+                println!("{:?}:{}: name contains a NUL byte", self.path, line_number);
+                return;
+            }
+        };
+
+        if source.starts_with("key:") {
+            let scancode = unsafe { SDL_GetScancodeFromName(name.as_ptr()) };
+            if scancode != SDL_SCANCODE_UNKNOWN {
+                for &(reserved, usage) in &RESERVED_KEYS {
+                    if reserved == scancode {
+                        println!("{:?}:{}: {} is used by the platform ({})",
+                                 self.path,
+                                 line_number,
+                                 &source[4..],
+                                 usage);
+                    }
+                }
+                self.keys.push((scancode, logical));
+            } else {
+                println!("{:?}:{}: unknown key {}", self.path, line_number, &source[4..]);
+            }
+        } else if source.starts_with("pad:") {
+            let raw = unsafe { SDL_GameControllerGetButtonFromString(name.as_ptr()) };
+            match SDL_GameControllerButton::from_raw(raw) {
+                Some(button) => self.pad_buttons.push((button, logical)),
+                None => {
+                    println!("{:?}:{}: unknown pad button {}",
+                             self.path,
+                             line_number,
+                             &source[4..])
+                }
+            }
+        } else {
+            println!("{:?}:{}: expected key: or pad:", self.path, line_number);
+        }
+    }
+}
+
+fn modified_time(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+fn default_keys() -> Vec<(SDL_Scancode, LogicalButton)> {
+    vec![(SDL_SCANCODE_W, LogicalButton::MoveUp),
+         (SDL_SCANCODE_A, LogicalButton::MoveLeft),
+         (SDL_SCANCODE_S, LogicalButton::MoveDown),
+         (SDL_SCANCODE_D, LogicalButton::MoveRight),
+         (SDL_SCANCODE_Q, LogicalButton::LeftShoulder),
+         (SDL_SCANCODE_E, LogicalButton::RightShoulder),
+         (SDL_SCANCODE_UP, LogicalButton::ActionUp),
+         (SDL_SCANCODE_LEFT, LogicalButton::ActionLeft),
+         (SDL_SCANCODE_RIGHT, LogicalButton::ActionRight),
+         (SDL_SCANCODE_DOWN, LogicalButton::ActionDown),
+         (SDL_SCANCODE_ESCAPE, LogicalButton::Back),
+         (SDL_SCANCODE_SPACE, LogicalButton::Start)]
+}
+
+fn default_pad_buttons() -> Vec<(SDL_GameControllerButton, LogicalButton)> {
+    use ffi::sdl::SDL_GameControllerButton::*;
+
+    vec![(SDL_CONTROLLER_BUTTON_DPAD_UP, LogicalButton::MoveUp),
+         (SDL_CONTROLLER_BUTTON_DPAD_DOWN, LogicalButton::MoveDown),
+         (SDL_CONTROLLER_BUTTON_DPAD_LEFT, LogicalButton::MoveLeft),
+         (SDL_CONTROLLER_BUTTON_DPAD_RIGHT, LogicalButton::MoveRight),
+         (SDL_CONTROLLER_BUTTON_Y, LogicalButton::ActionUp),
+         (SDL_CONTROLLER_BUTTON_A, LogicalButton::ActionDown),
+         (SDL_CONTROLLER_BUTTON_X, LogicalButton::ActionLeft),
+         (SDL_CONTROLLER_BUTTON_B, LogicalButton::ActionRight),
+         (SDL_CONTROLLER_BUTTON_LEFTSHOULDER, LogicalButton::LeftShoulder),
+         (SDL_CONTROLLER_BUTTON_RIGHTSHOULDER, LogicalButton::RightShoulder),
+         (SDL_CONTROLLER_BUTTON_START, LogicalButton::Start),
+         (SDL_CONTROLLER_BUTTON_BACK, LogicalButton::Back)]
+}
@@ -1,4 +1,10 @@
 use std::default::Default;
+use std::fmt;
+
+pub mod recording;
+pub mod timing;
+
+use self::timing::FrameStats;
 
 #[macro_export]
 macro_rules! make_array {
@@ -12,6 +18,15 @@ macro_rules! make_array {
     }}
 }
 
+// Logs through GameMemory::platform_log with the location of the call
+//     platform_log!(game_memory, context, LogLevel::Error, "No {}", name);
+#[macro_export]
+macro_rules! platform_log {
+    ( $memory:expr, $context:expr, $level:expr, $($arg:tt)+ ) => {{
+        ($memory.platform_log)($context, $level, file!(), line!(), format_args!($($arg)+))
+    }}
+}
+
 #[allow(dead_code)]
 pub mod util {
     use std::u32;
@@ -37,12 +52,44 @@ pub mod util {
         giga_bytes(tb) * 1024
     }
 
+    // The format of platform_list_files, every path followed by a NUL byte.
+    // Returns the size of the whole list, dest only gets filled if it fits.
+    pub fn pack_file_list(paths: &[String], dest: &mut [u8]) -> usize {
+        let size = paths.iter().map(|path| path.len() + 1).sum();
+        if size <= dest.len() {
+            let mut offset = 0;
+            for path in paths {
+                dest[offset..offset + path.len()].copy_from_slice(path.as_bytes());
+                dest[offset + path.len()] = 0;
+                offset += path.len() + 1;
+            }
+        }
+        size
+    }
+
 }
 
 pub type PlatformReadEntireFileT = fn(&ThreadContext, &str) -> Result<ReadFileResult, ()>;
 pub type PlatformFreeFileMemoryT = fn(&ThreadContext, *mut u8, u32);
 pub type PlatformWriteEntireFileT = fn(&ThreadContext, &str, u32, *mut u8) -> bool;
-
+pub type PlatformOpenFileT = fn(&ThreadContext, &str) -> Result<PlatformFile, ()>;
+// Fills the whole slice from the given offset, fails if the file is shorter
+pub type PlatformReadFileT = fn(&ThreadContext, &PlatformFile, u64, &mut [u8]) -> Result<(), ()>;
+pub type PlatformCloseFileT = fn(&ThreadContext, PlatformFile);
+// Paths of the files in a directory with the given extension (without the
+// dot), sorted by name. They go into the buffer of the caller, each one
+// followed by a NUL byte. Returns the size of the whole list, the buffer is
+// only filled if the list fits, so ask with an empty one first.
+pub type PlatformListFilesT = fn(&ThreadContext, &str, &str, &mut [u8]) -> Result<usize, ()>;
+// Level, source file, source line and the message
+pub type PlatformLogT = fn(&ThreadContext, LogLevel, &str, u32, fmt::Arguments);
+// Runs on a worker thread (or on the thread waiting for the queue) with the
+// data pointer that was handed to platform_add_work_entry
+pub type WorkQueueCallbackT = fn(&ThreadContext, *mut u8);
+pub type PlatformAddWorkEntryT = fn(*mut PlatformWorkQueue, WorkQueueCallbackT, *mut u8);
+pub type PlatformCompleteAllWorkT = fn(&ThreadContext, *mut PlatformWorkQueue);
+
+pub type GetGameAbiT = extern "C" fn() -> GameAbi;
 pub type GetSoundSamplesT = extern "C" fn(&ThreadContext, &mut GameMemory, &mut SoundBuffer);
 pub type UpdateAndRenderT = extern "C" fn(&ThreadContext,
                                           &mut GameMemory,
@@ -50,12 +97,56 @@ pub type UpdateAndRenderT = extern "C" fn(&ThreadContext,
                                           &mut VideoBuffer)
                                          ;
 
+// Bump whenever the layout of anything passed between the platform and the
+// game library (GameMemory, Input, VideoBuffer, SoundBuffer) changes. Also
+// when GameState gains a field that fits into its padding, the size check
+// can't see that one.
+pub const GAME_ABI_VERSION: u32 = 8;
+
+// Exported by the game library so the platform can refuse libraries built
+// against a different platform interface and notice when the GameState
+// layout changed under a running session.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct GameAbi {
+    pub version: u32,
+    pub game_state_size: u64,
+}
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
+impl LogLevel {
+    #[allow(dead_code)]
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warning => "WARNING",
+            LogLevel::Error => "ERROR",
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub struct ReadFileResult {
     pub size: u32,
     pub contents: *mut u8,
 }
 
+// An open file, only valid until it is handed to platform_close_file
+pub struct PlatformFile {
+    pub size: u64,
+    // The file descriptor on Linux, the HANDLE on Windows
+    pub handle: usize,
+}
+
 pub struct VideoBuffer<'a> {
     // Buffer memory is assumed to be BB GG RR xx
     pub memory: &'a mut [u32],
@@ -125,10 +216,17 @@ impl ControllerInput {
     }
 }
 
-pub struct ThreadContext;
+pub struct ThreadContext {
+    // 0 is the main thread, the worker threads count up from 1
+    pub index: u32,
+}
+
+// Only ever handled through pointers, the platform knows what is behind it
+pub enum PlatformWorkQueue {}
 
 pub const MAX_CONTROLLERS: usize = 5;
 
+#[derive(Copy, Clone)]
 pub struct Input {
     pub mouse_x: i32,
     pub mouse_y: i32,
@@ -171,9 +269,49 @@ impl Default for Input {
 #[allow(dead_code)]
 pub struct GameMemory<'a> {
     pub initialized: bool,
+    // Set by the platform when the memory was loaded from a crash dump of an
+    // earlier session. Everything the game state points to outside of the
+    // game memory (bitmaps, file memory) is gone and has to be loaded again,
+    // the game clears the flag once it did that.
+    pub restored: bool,
     pub permanent: &'a mut [u8], // REQUIRED to be zeroed
     pub transient: &'a mut [u8], // REQUIRED to be zeroed
     pub platform_read_entire_file: PlatformReadEntireFileT,
     pub platform_write_entire_file: PlatformWriteEntireFileT,
     pub platform_free_file_memory: PlatformFreeFileMemoryT,
+    pub platform_open_file: PlatformOpenFileT,
+    pub platform_read_file: PlatformReadFileT,
+    pub platform_close_file: PlatformCloseFileT,
+    pub platform_list_files: PlatformListFilesT,
+    pub platform_log: PlatformLogT,
+
+    // Entries on the high priority queue are expected to be finished within
+    // the frame, the low priority queue is for longer running work like
+    // loading assets
+    pub high_priority_queue: *mut PlatformWorkQueue,
+    pub low_priority_queue: *mut PlatformWorkQueue,
+    pub platform_add_work_entry: PlatformAddWorkEntryT,
+    pub platform_complete_all_work: PlatformCompleteAllWorkT,
+
+    // Written by the platform after every frame
+    pub frame_stats: FrameStats,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::util::pack_file_list;
+
+    #[test]
+    fn file_list_only_fills_a_buffer_that_fits() {
+        let paths = vec!["a/x.txt".to_string(), "a/y.txt".to_string()];
+
+        let mut small = [0xFF; 10];
+        assert_eq!(pack_file_list(&paths, &mut small), 16);
+        assert!(small.iter().all(|&byte| byte == 0xFF));
+
+        let mut buffer = [0xFF; 20];
+        assert_eq!(pack_file_list(&paths, &mut buffer), 16);
+        assert_eq!(&buffer[..16], b"a/x.txt\0a/y.txt\0");
+        assert_eq!(pack_file_list(&[], &mut []), 0);
+    }
 }
@@ -0,0 +1,473 @@
+// Input recording file format
+//
+// Everything is stored little endian, independent of the layout of the
+// structs in memory, so recordings survive changes to `Input` and can be
+// shared between machines and platforms.
+//
+// Header (36 bytes):
+//     magic            4 bytes  "RHIR"
+//     version          u32      RECORDING_VERSION
+//     frame_count      u32      number of frames that follow, 0 until
+//                               the recording was finished
+//     permanent_size   u64      size of the permanent store when recorded
+//     transient_size   u64      size of the transient store when recorded
+//     delta_t          f32      target seconds per frame when recorded
+//     controller_count u32      controllers stored per frame, at most
+//                               MAX_CONTROLLERS
+//
+// A session that crashed or got killed never patches frame_count. Frames have
+// a fixed size, so the reader counts the complete frames in the file instead.
+//
+// Frame:
+//     mouse_x, mouse_y, mouse_z                i32 each
+//     mouse_l, mouse_r, mouse_m, mouse_x1,
+//     mouse_x2                                 Button each
+//     delta_t                                  f32
+//     controller_count times ControllerInput
+//
+// ControllerInput:
+//     flags        u8   bit 0: is_connected, bit 1: average_x is set,
+//                       bit 2: average_y is set
+//     average_x    f32  0.0 if not set
+//     average_y    f32  0.0 if not set
+//     move_up, move_down, move_left, move_right,
+//     action_up, action_down, action_left, action_right,
+//     left_shoulder, right_shoulder, start, back      Button each
+//
+// Button:
+//     ended_down        u8  0 or 1
+//     half_transitions  u8
+
+#![allow(dead_code)]
+
+use std::cmp;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::u32;
+
+use super::{Input, ControllerInput, Button, MAX_CONTROLLERS};
+
+pub const RECORDING_MAGIC: [u8; 4] = *b"RHIR";
+pub const RECORDING_VERSION: u32 = 1;
+
+const HEADER_SIZE: u64 = 36;
+const FRAME_COUNT_OFFSET: u64 = 8;
+// Mouse position, mouse buttons and delta_t
+const FRAME_BASE_SIZE: u64 = 3 * 4 + 5 * 2 + 4;
+// Flags, averages and 12 buttons
+const CONTROLLER_SIZE: u64 = 1 + 2 * 4 + 12 * 2;
+
+const CONNECTED_BIT: u8 = 0b001;
+const AVERAGE_X_BIT: u8 = 0b010;
+const AVERAGE_Y_BIT: u8 = 0b100;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct RecordingHeader {
+    pub version: u32,
+    pub frame_count: u32,
+    pub permanent_size: u64,
+    pub transient_size: u64,
+    pub delta_t: f32,
+    pub controller_count: u32,
+}
+
+// Size of a recording with frame_count frames, for buffers that have to be
+// set aside before anything gets recorded
+pub fn recording_size(frame_count: u64) -> u64 {
+    HEADER_SIZE + frame_count * (FRAME_BASE_SIZE + MAX_CONTROLLERS as u64 * CONTROLLER_SIZE)
+}
+
+pub struct RecordingWriter<W: Write + Seek> {
+    out: W,
+    header: RecordingHeader,
+}
+
+impl<W: Write + Seek> RecordingWriter<W> {
+    pub fn new(mut out: W,
+               permanent_size: u64,
+               transient_size: u64,
+               delta_t: f32)
+               -> io::Result<RecordingWriter<W>> {
+        let header = RecordingHeader {
+            version: RECORDING_VERSION,
+            frame_count: 0,
+            permanent_size: permanent_size,
+            transient_size: transient_size,
+            delta_t: delta_t,
+            controller_count: MAX_CONTROLLERS as u32,
+        };
+
+        out.write_all(&RECORDING_MAGIC)?;
+        write_u32(&mut out, header.version)?;
+        write_u32(&mut out, header.frame_count)?;
+        write_u64(&mut out, header.permanent_size)?;
+        write_u64(&mut out, header.transient_size)?;
+        write_f32(&mut out, header.delta_t)?;
+        write_u32(&mut out, header.controller_count)?;
+
+        Ok(RecordingWriter {
+            out: out,
+            header: header,
+        })
+    }
+
+    pub fn header(&self) -> &RecordingHeader {
+        &self.header
+    }
+
+    pub fn write_input(&mut self, input: &Input) -> io::Result<()> {
+        write_i32(&mut self.out, input.mouse_x)?;
+        write_i32(&mut self.out, input.mouse_y)?;
+        write_i32(&mut self.out, input.mouse_z)?;
+        write_button(&mut self.out, &input.mouse_l)?;
+        write_button(&mut self.out, &input.mouse_r)?;
+        write_button(&mut self.out, &input.mouse_m)?;
+        write_button(&mut self.out, &input.mouse_x1)?;
+        write_button(&mut self.out, &input.mouse_x2)?;
+        write_f32(&mut self.out, input.delta_t)?;
+
+        for controller in &input.controllers {
+            write_controller(&mut self.out, controller)?;
+        }
+
+        self.header.frame_count += 1;
+        Ok(())
+    }
+
+    // Patches the frame count into the header. Until this is called the
+    // recording on disk claims to hold no frames at all.
+    pub fn finish(mut self) -> io::Result<W> {
+        let end = self.out.seek(SeekFrom::Current(0))?;
+        self.out.seek(SeekFrom::Start(FRAME_COUNT_OFFSET))?;
+        write_u32(&mut self.out, self.header.frame_count)?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+pub struct RecordingReader<R: Read + Seek> {
+    input: R,
+    header: RecordingHeader,
+    frames_read: u32,
+}
+
+impl<R: Read + Seek> RecordingReader<R> {
+    pub fn new(mut input: R) -> io::Result<RecordingReader<R>> {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if magic != RECORDING_MAGIC {
+            return Err(invalid_data("Not an input recording"));
+        }
+
+        let version = read_u32(&mut input)?;
+        if version != RECORDING_VERSION {
+            return Err(invalid_data("Unsupported input recording version"));
+        }
+
+        let mut header = RecordingHeader {
+            version: version,
+            frame_count: read_u32(&mut input)?,
+            permanent_size: read_u64(&mut input)?,
+            transient_size: read_u64(&mut input)?,
+            delta_t: read_f32(&mut input)?,
+            controller_count: read_u32(&mut input)?,
+        };
+        if header.controller_count as usize > MAX_CONTROLLERS {
+            return Err(invalid_data("Too many controllers in the input recording"));
+        }
+
+        // Unfinished recordings claim no frames, truncated ones too many
+        let end = input.seek(SeekFrom::End(0))?;
+        input.seek(SeekFrom::Start(HEADER_SIZE))?;
+        let frame_size = FRAME_BASE_SIZE + header.controller_count as u64 * CONTROLLER_SIZE;
+        let complete_frames = end.saturating_sub(HEADER_SIZE) / frame_size;
+        if header.frame_count == 0 || header.frame_count as u64 > complete_frames {
+            header.frame_count = cmp::min(complete_frames, u32::MAX as u64) as u32;
+        }
+
+        Ok(RecordingReader {
+            input: input,
+            header: header,
+            frames_read: 0,
+        })
+    }
+
+    pub fn header(&self) -> &RecordingHeader {
+        &self.header
+    }
+
+    pub fn frames_read(&self) -> u32 {
+        self.frames_read
+    }
+
+    // Returns false without touching the input once all recorded frames
+    // are consumed.
+    pub fn read_input(&mut self, input: &mut Input) -> io::Result<bool> {
+        if self.frames_read >= self.header.frame_count {
+            return Ok(false);
+        }
+
+        input.mouse_x = read_i32(&mut self.input)?;
+        input.mouse_y = read_i32(&mut self.input)?;
+        input.mouse_z = read_i32(&mut self.input)?;
+        read_button(&mut self.input, &mut input.mouse_l)?;
+        read_button(&mut self.input, &mut input.mouse_r)?;
+        read_button(&mut self.input, &mut input.mouse_m)?;
+        read_button(&mut self.input, &mut input.mouse_x1)?;
+        read_button(&mut self.input, &mut input.mouse_x2)?;
+        input.delta_t = read_f32(&mut self.input)?;
+
+        // Recordings with fewer controllers leave the rest disconnected
+        for index in 0..self.header.controller_count as usize {
+            read_controller(&mut self.input, &mut input.controllers[index])?;
+        }
+        for index in self.header.controller_count as usize..input.controllers.len() {
+            input.controllers[index] = ControllerInput::default();
+        }
+
+        self.frames_read += 1;
+        Ok(true)
+    }
+
+    pub fn rewind(&mut self) -> io::Result<()> {
+        self.input.seek(SeekFrom::Start(HEADER_SIZE))?;
+        self.frames_read = 0;
+        Ok(())
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_controller<W: Write>(out: &mut W, controller: &ControllerInput) -> io::Result<()> {
+    let mut flags = 0;
+    if controller.is_connected {
+        flags |= CONNECTED_BIT;
+    }
+    if controller.average_x.is_some() {
+        flags |= AVERAGE_X_BIT;
+    }
+    if controller.average_y.is_some() {
+        flags |= AVERAGE_Y_BIT;
+    }
+    out.write_all(&[flags])?;
+    write_f32(out, controller.average_x.unwrap_or(0.0))?;
+    write_f32(out, controller.average_y.unwrap_or(0.0))?;
+
+    for button in &[&controller.move_up,
+                    &controller.move_down,
+                    &controller.move_left,
+                    &controller.move_right,
+                    &controller.action_up,
+                    &controller.action_down,
+                    &controller.action_left,
+                    &controller.action_right,
+                    &controller.left_shoulder,
+                    &controller.right_shoulder,
+                    &controller.start,
+                    &controller.back] {
+        write_button(out, button)?;
+    }
+    Ok(())
+}
+
+fn read_controller<R: Read>(input: &mut R, controller: &mut ControllerInput) -> io::Result<()> {
+    let mut flags = [0u8; 1];
+    input.read_exact(&mut flags)?;
+    let flags = flags[0];
+
+    let average_x = read_f32(input)?;
+    let average_y = read_f32(input)?;
+    controller.is_connected = (flags & CONNECTED_BIT) != 0;
+    controller.average_x = if (flags & AVERAGE_X_BIT) != 0 {
+        Some(average_x)
+    } else {
+        None
+    };
+    controller.average_y = if (flags & AVERAGE_Y_BIT) != 0 {
+        Some(average_y)
+    } else {
+        None
+    };
+
+    read_button(input, &mut controller.move_up)?;
+    read_button(input, &mut controller.move_down)?;
+    read_button(input, &mut controller.move_left)?;
+    read_button(input, &mut controller.move_right)?;
+    read_button(input, &mut controller.action_up)?;
+    read_button(input, &mut controller.action_down)?;
+    read_button(input, &mut controller.action_left)?;
+    read_button(input, &mut controller.action_right)?;
+    read_button(input, &mut controller.left_shoulder)?;
+    read_button(input, &mut controller.right_shoulder)?;
+    read_button(input, &mut controller.start)?;
+    read_button(input, &mut controller.back)?;
+    Ok(())
+}
+
+fn write_button<W: Write>(out: &mut W, button: &Button) -> io::Result<()> {
+    out.write_all(&[button.ended_down as u8, button.half_transitions])
+}
+
+fn read_button<R: Read>(input: &mut R, button: &mut Button) -> io::Result<()> {
+    let mut bytes = [0u8; 2];
+    input.read_exact(&mut bytes)?;
+    button.ended_down = bytes[0] != 0;
+    button.half_transitions = bytes[1];
+    Ok(())
+}
+
+fn write_u32<W: Write>(out: &mut W, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_i32<W: Write>(out: &mut W, value: i32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W: Write>(out: &mut W, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_f32<W: Write>(out: &mut W, value: f32) -> io::Result<()> {
+    write_u32(out, value.to_bits())
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_i32<R: Read>(input: &mut R) -> io::Result<i32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(i32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32<R: Read>(input: &mut R) -> io::Result<f32> {
+    Ok(f32::from_bits(read_u32(input)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use super::super::{Input, MAX_CONTROLLERS};
+
+    fn test_input(frame: i32) -> Input {
+        let mut input = Input::default();
+        input.mouse_x = frame;
+        input.mouse_y = -frame;
+        input.mouse_l.ended_down = frame % 2 == 0;
+        input.mouse_l.half_transitions = 1;
+        input.delta_t = 1.0 / 30.0;
+        input.controllers[1].is_connected = true;
+        input.controllers[1].average_x = Some(0.25 * frame as f32);
+        input.controllers[1].start.ended_down = true;
+        input.controllers[1].start.half_transitions = 3;
+        input
+    }
+
+    fn assert_same_input(a: &Input, b: &Input) {
+        assert_eq!(a.mouse_x, b.mouse_x);
+        assert_eq!(a.mouse_y, b.mouse_y);
+        assert_eq!(a.mouse_l.ended_down, b.mouse_l.ended_down);
+        assert_eq!(a.mouse_l.half_transitions, b.mouse_l.half_transitions);
+        assert_eq!(a.delta_t, b.delta_t);
+        for (a, b) in a.controllers.iter().zip(b.controllers.iter()) {
+            assert_eq!(a.is_connected, b.is_connected);
+            assert_eq!(a.average_x, b.average_x);
+            assert_eq!(a.average_y, b.average_y);
+            assert_eq!(a.start.ended_down, b.start.ended_down);
+            assert_eq!(a.start.half_transitions, b.start.half_transitions);
+        }
+    }
+
+    fn record(frames: i32, finish: bool) -> Vec<u8> {
+        let mut writer = RecordingWriter::new(Cursor::new(Vec::new()), 1024, 4096, 1.0 / 30.0)
+                             .unwrap();
+        for frame in 0..frames {
+            writer.write_input(&test_input(frame)).unwrap();
+        }
+        if finish {
+            writer.finish().unwrap().into_inner()
+        } else {
+            writer.out.into_inner()
+        }
+    }
+
+    fn read_all(bytes: Vec<u8>) -> Vec<Input> {
+        let mut reader = RecordingReader::new(Cursor::new(bytes)).unwrap();
+        let mut inputs = Vec::new();
+        let mut input = Input::default();
+        while reader.read_input(&mut input).unwrap() {
+            inputs.push(input);
+        }
+        inputs
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = record(3, true);
+        let reader = RecordingReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(*reader.header(),
+                   RecordingHeader {
+                       version: RECORDING_VERSION,
+                       frame_count: 3,
+                       permanent_size: 1024,
+                       transient_size: 4096,
+                       delta_t: 1.0 / 30.0,
+                       controller_count: MAX_CONTROLLERS as u32,
+                   });
+
+        let inputs = read_all(bytes);
+        assert_eq!(inputs.len(), 3);
+        for (frame, input) in inputs.iter().enumerate() {
+            assert_same_input(input, &test_input(frame as i32));
+        }
+    }
+
+    #[test]
+    fn recording_size_matches_the_writer() {
+        assert_eq!(record(0, true).len() as u64, recording_size(0));
+        assert_eq!(record(5, true).len() as u64, recording_size(5));
+    }
+
+    #[test]
+    fn unfinished_recording_keeps_its_frames() {
+        let mut bytes = record(4, false);
+        // Half a frame from a session that died while writing
+        bytes.extend_from_slice(&[0; 10]);
+        assert_eq!(read_all(bytes).len(), 4);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = record(1, true);
+        bytes[0] = b'X';
+        assert!(RecordingReader::new(Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_version() {
+        let mut bytes = record(1, true);
+        bytes[4..8].copy_from_slice(&(RECORDING_VERSION + 1).to_le_bytes());
+        assert!(RecordingReader::new(Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn rejects_too_many_controllers() {
+        let mut bytes = record(1, true);
+        bytes[32..36].copy_from_slice(&(MAX_CONTROLLERS as u32 + 1).to_le_bytes());
+        assert!(RecordingReader::new(Cursor::new(bytes)).is_err());
+    }
+}
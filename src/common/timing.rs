@@ -0,0 +1,133 @@
+// Frame timing statistics
+//
+// The platform layer measures every frame and keeps the last
+// FRAME_STATS_COUNT of them in a ring inside GameMemory, so the game can
+// draw a frame-pacing overlay from the same data the platform reports.
+// All durations are in seconds.
+
+pub const FRAME_STATS_COUNT: usize = 120;
+
+#[derive(Default, Copy, Clone)]
+pub struct FrameTiming {
+    // update_and_render
+    pub update: f32,
+    // get_sound_samples and handing the samples to the audio output
+    pub sound: f32,
+    // Uploading and presenting the backbuffer
+    pub present: f32,
+    // Sleeping and spinning until the frame time is used up
+    pub sleep: f32,
+    // The whole frame, from the end of the previous one
+    pub total: f32,
+    // The work alone took longer than the target frame time
+    pub missed: bool,
+}
+
+#[derive(Copy, Clone)]
+pub struct FrameStats {
+    pub target_seconds: f32,
+    pub timings: [FrameTiming; FRAME_STATS_COUNT],
+    // Slot the next frame is written to, the newest frame is the one before
+    pub next: usize,
+    pub frame_count: u64,
+    pub missed_frames: u64,
+
+    // Over the whole session, for the report on exit
+    pub sum: FrameTiming,
+    pub max: FrameTiming,
+}
+
+#[allow(dead_code)]
+impl FrameStats {
+    pub fn new(target_seconds: f32) -> FrameStats {
+        FrameStats {
+            target_seconds: target_seconds,
+            timings: [Default::default(); FRAME_STATS_COUNT],
+            next: 0,
+            frame_count: 0,
+            missed_frames: 0,
+            sum: Default::default(),
+            max: Default::default(),
+        }
+    }
+
+    pub fn push(&mut self, timing: FrameTiming) {
+        self.timings[self.next] = timing;
+        self.next = (self.next + 1) % FRAME_STATS_COUNT;
+        self.frame_count += 1;
+        if timing.missed {
+            self.missed_frames += 1;
+        }
+
+        self.sum.update += timing.update;
+        self.sum.sound += timing.sound;
+        self.sum.present += timing.present;
+        self.sum.sleep += timing.sleep;
+        self.sum.total += timing.total;
+
+        self.max.update = self.max.update.max(timing.update);
+        self.max.sound = self.max.sound.max(timing.sound);
+        self.max.present = self.max.present.max(timing.present);
+        self.max.sleep = self.max.sleep.max(timing.sleep);
+        self.max.total = self.max.total.max(timing.total);
+    }
+
+    // Number of valid entries in timings
+    pub fn len(&self) -> usize {
+        if self.frame_count < FRAME_STATS_COUNT as u64 {
+            self.frame_count as usize
+        } else {
+            FRAME_STATS_COUNT
+        }
+    }
+
+    // index 0 is the oldest recorded frame
+    pub fn get(&self, index: usize) -> Option<&FrameTiming> {
+        let len = self.len();
+        if index < len {
+            Some(&self.timings[(self.next + FRAME_STATS_COUNT - len + index) % FRAME_STATS_COUNT])
+        } else {
+            None
+        }
+    }
+
+    // Summary of the whole session, meant for the end of it
+    pub fn print_report(&self) {
+        if self.frame_count == 0 {
+            return;
+        }
+
+        let frames = self.frame_count as f32;
+        let ms = |seconds: f32| seconds * 1000.0;
+        println!("Frame timing over {} frames (target {:.2}ms):",
+                 self.frame_count,
+                 ms(self.target_seconds));
+        println!("    update   avg {:7.2}ms  max {:7.2}ms",
+                 ms(self.sum.update / frames),
+                 ms(self.max.update));
+        println!("    sound    avg {:7.2}ms  max {:7.2}ms",
+                 ms(self.sum.sound / frames),
+                 ms(self.max.sound));
+        println!("    present  avg {:7.2}ms  max {:7.2}ms",
+                 ms(self.sum.present / frames),
+                 ms(self.max.present));
+        println!("    sleep    avg {:7.2}ms  max {:7.2}ms",
+                 ms(self.sum.sleep / frames),
+                 ms(self.max.sleep));
+        println!("    frame    avg {:7.2}ms  max {:7.2}ms",
+                 ms(self.sum.total / frames),
+                 ms(self.max.total));
+        println!("    missed   {} ({:.1}%)",
+                 self.missed_frames,
+                 self.missed_frames as f32 * 100.0 / frames);
+    }
+
+    pub fn latest(&self) -> Option<&FrameTiming> {
+        let len = self.len();
+        if len > 0 {
+            self.get(len - 1)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,530 @@
+// Crash dumps
+//
+// Every frame the platform hands the input the game is about to get to
+// record_frame. The input of the last HISTORY_FRAMES to 2 * HISTORY_FRAMES
+// frames is kept together with a copy of the permanent memory from before
+// the first of them. When the process gets a fatal signal or something
+// panics for good, the directory crash_<unix time>_<pid> in the crash
+// directory gets:
+//
+//     crash.txt       what happened and a backtrace
+//     memory.bin      the permanent memory when the crash hit
+//     snapshot.bin    the permanent memory before the first recorded frame
+//     input.rhi       the recorded frames, in the input recording format
+//     dump.info       frame numbers and initialized flags of both blocks
+//
+// --resume-crash continues the game from memory.bin, --replay-crash starts
+// from snapshot.bin and plays input.rhi on top of it, which should run into
+// the same crash again.
+//
+// The handler has to stick to async-signal-safe calls, so everything the
+// dump needs is set up by install: the directory with all files already
+// open and the buffers the text and the recording get formatted into. The
+// handler itself only formats into those buffers and hands them to
+// write(2). It runs on an alternate stack, which every thread has to get
+// from install_signal_stack, so a stack overflow gets dumped as well.
+//
+// A session that ends normally removes its unused dump again. Until a dump
+// is complete its directory holds the file incomplete, which the session
+// keeps locked. Sessions that got killed or crashed while dumping leave it
+// unlocked, and the next install removes their directories.
+
+use libc::{c_int, c_void, flock, mmap, raise, sigaction, sigaltstack, sigemptyset, siginfo_t};
+use libc::{stack_t, unlink, write};
+use libc::{LOCK_EX, LOCK_NB, MAP_PRIVATE, MAP_ANON, MAP_FAILED, PROT_READ, PROT_WRITE};
+use libc::{SA_ONSTACK, SA_RESETHAND, SA_SIGINFO, SIGABRT, SIGBUS, SIGFPE, SIGILL, SIGSEGV};
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::mem;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::AsRawFd;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::slice;
+use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ffi::linux;
+use common::Input;
+use common::recording::{self, RecordingReader, RecordingWriter};
+
+// Taking a snapshot copies the whole permanent memory, so it only happens
+// once every HISTORY_FRAMES
+const HISTORY_FRAMES: usize = 300;
+const MAX_BACKTRACE_FRAMES: usize = 64;
+// Longer reasons (panic messages) get cut off
+const TEXT_BUFFER_SIZE: usize = 4096;
+// Room for the formatting and the backtrace in the handler
+const SIGNAL_STACK_SIZE: usize = 64 * 1024;
+
+pub const MEMORY_FILE: &'static str = "memory.bin";
+pub const SNAPSHOT_FILE: &'static str = "snapshot.bin";
+const INPUT_FILE: &'static str = "input.rhi";
+const INFO_FILE: &'static str = "dump.info";
+const CRASH_FILE: &'static str = "crash.txt";
+const INCOMPLETE_FILE: &'static str = "incomplete";
+
+const FATAL_SIGNALS: [c_int; 5] = [SIGSEGV, SIGBUS, SIGFPE, SIGILL, SIGABRT];
+
+struct History {
+    snapshot: *mut u8,
+    // GameMemory::initialized when the snapshot was taken
+    initialized: bool,
+    first_frame: u64,
+    inputs: Vec<Input>,
+}
+
+// Opened by install, written only when the process crashes
+struct DumpFiles {
+    dir: PathBuf,
+    // Locked until the process exits, removed by a complete dump. Only
+    // held open for the lock.
+    #[allow(dead_code)]
+    incomplete: File,
+    incomplete_path: CString,
+    crash: File,
+    memory: File,
+    snapshot: File,
+    input: File,
+    info: File,
+}
+
+struct CrashState {
+    files: DumpFiles,
+    text: Vec<u8>,
+    recording: Vec<u8>,
+    permanent: *const u8,
+    permanent_size: usize,
+    transient_size: usize,
+    delta_t: f32,
+    frame: u64,
+    initialized: bool,
+    // Two spans that take turns, the one not written to is the older one
+    histories: [History; 2],
+    current: usize,
+}
+
+// Only the main thread touches the histories, the handlers just read them
+// and fill the buffers
+static mut STATE: *mut CrashState = 0 as *mut CrashState;
+static DUMPING: AtomicBool = ATOMIC_BOOL_INIT;
+
+pub struct DumpInfo {
+    pub frame: u64,
+    pub memory_initialized: bool,
+    pub snapshot_frame: u64,
+    pub snapshot_initialized: bool,
+}
+
+impl History {
+    fn new(permanent_size: usize) -> History {
+        let snapshot = unsafe {
+            // Allocation implicitly freed at the end of the execution
+            mmap(ptr::null_mut(),
+                 permanent_size,
+                 PROT_READ | PROT_WRITE,
+                 MAP_PRIVATE | MAP_ANON,
+                 -1,
+                 0)
+        };
+        if snapshot.is_null() || snapshot == MAP_FAILED {
+            panic!("Memory for the crash snapshots could not be obtained!");
+        }
+
+        History {
+            snapshot: snapshot as *mut u8,
+            initialized: false,
+            first_frame: 0,
+            inputs: Vec::with_capacity(HISTORY_FRAMES),
+        }
+    }
+
+    fn start(&mut self, permanent: *const u8, size: usize, initialized: bool, frame: u64) {
+        unsafe {
+            ptr::copy_nonoverlapping(permanent, self.snapshot, size);
+        }
+        self.initialized = initialized;
+        self.first_frame = frame;
+        self.inputs.clear();
+    }
+}
+
+impl DumpFiles {
+    fn create(dir: PathBuf) -> io::Result<DumpFiles> {
+        // Never reuses the directory of another session
+        fs::create_dir(&dir)?;
+        let incomplete_path = dir.join(INCOMPLETE_FILE);
+        let incomplete = File::create(&incomplete_path)?;
+        if unsafe { flock(incomplete.as_raw_fd(), LOCK_EX | LOCK_NB) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(DumpFiles {
+            incomplete: incomplete,
+            incomplete_path: CString::new(incomplete_path.into_os_string().into_vec())?,
+            crash: File::create(dir.join(CRASH_FILE))?,
+            memory: File::create(dir.join(MEMORY_FILE))?,
+            snapshot: File::create(dir.join(SNAPSHOT_FILE))?,
+            input: File::create(dir.join(INPUT_FILE))?,
+            info: File::create(dir.join(INFO_FILE))?,
+            dir: dir,
+        })
+    }
+}
+
+impl CrashState {
+    // Runs inside the signal handler, nothing in here may allocate
+    fn write_dump(&mut self, reason: fmt::Arguments) -> bool {
+        // The backtrace first, anything after it may fault again
+        let length = format_text(&mut self.text,
+                                 format_args!("{} at frame {}\n\n", reason, self.frame));
+        let mut written = write_all(&self.files.crash, &self.text[..length]);
+        write_backtrace(&self.files.crash);
+
+        written &= write_memory(&self.files.memory, self.permanent, self.permanent_size);
+
+        let older = &self.histories[1 - self.current];
+        let current = &self.histories[self.current];
+        let use_older = older.inputs.len() == HISTORY_FRAMES;
+        let start = if use_older { older } else { current };
+        written &= write_memory(&self.files.snapshot, start.snapshot, self.permanent_size);
+
+        // Sized for both histories by install, so the cursor never runs out
+        let recording_length = {
+            let inputs: &[Input] = if use_older { &older.inputs } else { &[] };
+            let result = RecordingWriter::new(Cursor::new(&mut self.recording[..]),
+                                              self.permanent_size as u64,
+                                              self.transient_size as u64,
+                                              self.delta_t)
+                             .and_then(|mut recorder| {
+                                 for input in inputs.iter().chain(current.inputs.iter()) {
+                                     recorder.write_input(input)?;
+                                 }
+                                 recorder.finish()
+                             });
+            result.map(|cursor| cursor.position() as usize).unwrap_or(0)
+        };
+        written &= recording_length > 0 &&
+                   write_all(&self.files.input, &self.recording[..recording_length]);
+
+        let length = format_text(&mut self.text,
+                                 format_args!("frame={}\nmemory_initialized={}\n\
+                                               snapshot_frame={}\nsnapshot_initialized={}\n",
+                                              self.frame,
+                                              self.initialized as u8,
+                                              start.first_frame,
+                                              start.initialized as u8));
+        written &= write_all(&self.files.info, &self.text[..length]);
+        written && unsafe { unlink(self.files.incomplete_path.as_ptr()) } == 0
+    }
+}
+
+// Formats into a buffer that was allocated up front, whatever doesn't fit
+// gets cut off. Returns the length of the text.
+fn format_text(buffer: &mut [u8], text: fmt::Arguments) -> usize {
+    let mut cursor = Cursor::new(buffer);
+    let _ = cursor.write_fmt(text);
+    cursor.position() as usize
+}
+
+// Plain write(2) in a loop, std::io might allocate for its errors
+fn write_all(file: &File, mut bytes: &[u8]) -> bool {
+    while !bytes.is_empty() {
+        let result = unsafe {
+            write(file.as_raw_fd(), bytes.as_ptr() as *const c_void, bytes.len())
+        };
+        if result <= 0 {
+            return false;
+        }
+        bytes = &bytes[result as usize..];
+    }
+    true
+}
+
+fn write_memory(file: &File, memory: *const u8, size: usize) -> bool {
+    write_all(file, unsafe { slice::from_raw_parts(memory, size) })
+}
+
+fn write_backtrace(file: &File) {
+    let mut frames = [ptr::null_mut::<c_void>(); MAX_BACKTRACE_FRAMES];
+    unsafe {
+        let count = linux::backtrace(frames.as_mut_ptr(), frames.len() as c_int);
+        linux::backtrace_symbols_fd(frames.as_ptr(), count, file.as_raw_fd());
+    }
+}
+
+fn write_stderr(bytes: &[u8]) {
+    unsafe {
+        write(2, bytes.as_ptr() as *const c_void, bytes.len());
+    }
+}
+
+fn dump(reason: fmt::Arguments) {
+    // A panic that aborts comes by here twice, and so does a crash while
+    // dumping
+    if DUMPING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let state = match unsafe { STATE.as_mut() } {
+        Some(state) => state,
+        None => return,
+    };
+
+    let written = state.write_dump(reason);
+    // The dump is done with the text buffer, it holds the message now
+    let length = if written {
+        format_text(&mut state.text,
+                    format_args!("{}, crash dump written to {:?}\n", reason, state.files.dir))
+    } else {
+        format_text(&mut state.text,
+                    format_args!("{}, the crash dump could not be written! ({:?})\n",
+                                 reason,
+                                 state.files.dir))
+    };
+    write_stderr(&state.text[..length]);
+}
+
+fn signal_name(signal: c_int) -> &'static str {
+    match signal {
+        SIGSEGV => "SIGSEGV",
+        SIGBUS => "SIGBUS",
+        SIGFPE => "SIGFPE",
+        SIGILL => "SIGILL",
+        SIGABRT => "SIGABRT",
+        _ => "Signal",
+    }
+}
+
+extern "C" fn signal_handler(signal: c_int, info: *mut siginfo_t, _context: *mut c_void) {
+    let address = unsafe { (*info).si_addr() };
+    dump(format_args!("{} at address {:?}", signal_name(signal), address));
+
+    // SA_RESETHAND already put the default action back. The signal stays
+    // blocked until the handler returns, then the process dies the way it
+    // would have without the handler.
+    unsafe {
+        raise(signal);
+    }
+}
+
+// Dumps that still have their incomplete file unlocked come from sessions
+// that are gone
+fn remove_incomplete_dumps(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let incomplete = match File::open(path.join(INCOMPLETE_FILE)) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        if unsafe { flock(incomplete.as_raw_fd(), LOCK_EX | LOCK_NB) } != 0 {
+            continue;
+        }
+        println!("Removing the incomplete crash dump {:?}", path);
+        if let Err(error) = fs::remove_dir_all(&path) {
+            println!("The incomplete crash dump {:?} could not be removed! ({})",
+                     path,
+                     error);
+        }
+    }
+}
+
+// The handlers run on the alternate stack of the thread that crashed. Call
+// this at the start of every thread, install does it for the thread it runs
+// on.
+pub fn install_signal_stack() {
+    let stack = unsafe {
+        // Allocation implicitly freed at the end of the execution
+        mmap(ptr::null_mut(),
+             SIGNAL_STACK_SIZE,
+             PROT_READ | PROT_WRITE,
+             MAP_PRIVATE | MAP_ANON,
+             -1,
+             0)
+    };
+    let result = if stack.is_null() || stack == MAP_FAILED {
+        -1
+    } else {
+        unsafe {
+            let mut signal_stack: stack_t = mem::zeroed();
+            signal_stack.ss_sp = stack;
+            signal_stack.ss_size = SIGNAL_STACK_SIZE;
+            sigaltstack(&signal_stack, ptr::null_mut())
+        }
+    };
+    if result != 0 {
+        println!("The signal stack of thread {:?} could not be set up, a stack overflow won't \
+                  be dumped!",
+                 thread::current().name());
+    }
+}
+
+// Takes the first snapshot right away, so call it once the game memory is
+// set up
+pub fn install(dir: PathBuf,
+               permanent: *const u8,
+               permanent_size: usize,
+               transient_size: usize,
+               delta_t: f32,
+               initialized: bool) {
+    remove_incomplete_dumps(&dir);
+
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs());
+    let dump_name = match seconds {
+        Ok(seconds) => format!("crash_{}_{}", seconds, process::id()),
+        Err(_) => format!("crash_{}", process::id()),
+    };
+    let dump_dir = dir.join(dump_name);
+    let files = match fs::create_dir_all(&dir).and_then(|_| DumpFiles::create(dump_dir)) {
+        Ok(files) => files,
+        Err(error) => {
+            println!("The crash dump files could not be created, crashes won't be dumped! ({})",
+                     error);
+            return;
+        }
+    };
+
+    // glibc loads the unwinder on the first backtrace, which allocates.
+    // Better here than in the handler.
+    let mut frames = [ptr::null_mut::<c_void>(); MAX_BACKTRACE_FRAMES];
+    unsafe {
+        linux::backtrace(frames.as_mut_ptr(), frames.len() as c_int);
+    }
+
+    let recording_size = recording::recording_size(2 * HISTORY_FRAMES as u64);
+    let mut state = Box::new(CrashState {
+        files: files,
+        text: vec![0; TEXT_BUFFER_SIZE],
+        recording: vec![0; recording_size as usize],
+        permanent: permanent,
+        permanent_size: permanent_size,
+        transient_size: transient_size,
+        delta_t: delta_t,
+        frame: 0,
+        initialized: initialized,
+        histories: [History::new(permanent_size), History::new(permanent_size)],
+        current: 0,
+    });
+    state.histories[0].start(permanent, permanent_size, initialized, 0);
+    // Never freed, a crash can happen until the very end
+    unsafe {
+        STATE = Box::into_raw(state);
+    }
+
+    install_signal_stack();
+    for &signal in &FATAL_SIGNALS {
+        let result = unsafe {
+            let mut action: sigaction = mem::zeroed();
+            action.sa_sigaction = signal_handler as usize;
+            action.sa_flags = SA_SIGINFO | SA_RESETHAND | SA_ONSTACK;
+            sigemptyset(&mut action.sa_mask);
+            sigaction(signal, &action, ptr::null_mut())
+        };
+        if result != 0 {
+            println!("The crash handler for {} could not be installed!", signal_name(signal));
+        }
+    }
+
+    // The game library shares the standard library with the platform, so
+    // this sees its panics as well. Only the ones on the main thread end
+    // the process, nothing catches them there. Other threads just die, and
+    // the work queues catch the panics of their entries. A panic that
+    // aborts gets dumped by the SIGABRT handler otherwise.
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if cfg!(panic = "abort") || thread::current().name() == Some("main") {
+            dump(format_args!("Panic: {}", info));
+        }
+        default_hook(info);
+    }));
+}
+
+// Call at the very end of a session that didn't crash, removes the files
+// install created for the dump. The handlers stay in place but won't dump
+// anymore.
+pub fn discard_dump() {
+    if DUMPING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let state = match unsafe { STATE.as_ref() } {
+        Some(state) => state,
+        None => return,
+    };
+    if let Err(error) = fs::remove_dir_all(&state.files.dir) {
+        println!("The unused crash dump {:?} could not be removed! ({})",
+                 state.files.dir,
+                 error);
+    }
+}
+
+// Call once per frame right before update_and_render with the input the game
+// is about to get
+pub fn record_frame(input: &Input, initialized: bool) {
+    let state = match unsafe { STATE.as_mut() } {
+        Some(state) => state,
+        None => return,
+    };
+
+    if state.histories[state.current].inputs.len() == HISTORY_FRAMES {
+        state.current = 1 - state.current;
+        let (permanent, size, frame) = (state.permanent, state.permanent_size, state.frame);
+        state.histories[state.current].start(permanent, size, initialized, frame);
+    }
+    state.histories[state.current].inputs.push(*input);
+    state.frame += 1;
+    state.initialized = initialized;
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn read_info(dir: &Path) -> io::Result<DumpInfo> {
+    let mut info = DumpInfo {
+        frame: 0,
+        memory_initialized: false,
+        snapshot_frame: 0,
+        snapshot_initialized: false,
+    };
+
+    let file = File::open(dir.join(INFO_FILE))?;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        let number = value.parse::<u64>()
+                          .map_err(|_| invalid_data(format!("Bad value in dump.info: {}", line)))?;
+        match key {
+            "frame" => info.frame = number,
+            "memory_initialized" => info.memory_initialized = number != 0,
+            "snapshot_frame" => info.snapshot_frame = number,
+            "snapshot_initialized" => info.snapshot_initialized = number != 0,
+            _ => println!("Unknown key in dump.info: {}", key),
+        }
+    }
+    Ok(info)
+}
+
+// Fills memory with memory.bin or snapshot.bin of a dump, the dump has to
+// come from a session with the same permanent memory size
+pub fn load_memory(dir: &Path, name: &str, memory: &mut [u8]) -> io::Result<()> {
+    let mut file = File::open(dir.join(name))?;
+    if file.metadata()?.len() != memory.len() as u64 {
+        return Err(invalid_data(format!("{} was written with a different permanent memory size",
+                                        name)));
+    }
+    file.read_exact(memory)
+}
+
+pub fn open_input(dir: &Path) -> io::Result<RecordingReader<File>> {
+    File::open(dir.join(INPUT_FILE)).and_then(RecordingReader::new)
+}
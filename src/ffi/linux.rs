@@ -35,4 +35,8 @@ extern "C" {
     pub fn dlclose(handle: *mut c_void) -> c_int;
     fn dlerror() -> *const c_char;
     pub fn sendfile(out_fd: c_int, in_fd: c_int, offset: *mut off_t, count: size_t) -> ssize_t;
+
+    // glibc execinfo
+    pub fn backtrace(buffer: *mut *mut c_void, size: c_int) -> c_int;
+    pub fn backtrace_symbols_fd(buffer: *const *mut c_void, size: c_int, fd: c_int);
 }
@@ -68,6 +68,7 @@ pub const OPEN_EXISTING: DWORD = 3;
 pub const FILE_BEGIN: DWORD = 0;
 
 pub const FILE_ATTRIBUTE_NORMAL: DWORD = 0x00000080;
+pub const FILE_ATTRIBUTE_DIRECTORY: DWORD = 0x00000010;
 pub const GENERIC_READ: DWORD = 0x80000000;
 pub const GENERIC_WRITE: DWORD = 0x40000000;
 
@@ -238,6 +239,20 @@ pub struct WIN32_FILE_ATTRIBUTE_DATA {
     pub nFileSizeLow: DWORD,
 }
 
+#[repr(C)]
+pub struct WIN32_FIND_DATAA {
+    pub dwFileAttributes: DWORD,
+    pub ftCreationTime: FILETIME,
+    pub ftLastAccessTime: FILETIME,
+    pub ftLastWriteTime: FILETIME,
+    pub nFileSizeHigh: DWORD,
+    pub nFileSizeLow: DWORD,
+    pub dwReserved0: DWORD,
+    pub dwReserved1: DWORD,
+    pub cFileName: [CHAR; MAX_PATH],
+    pub cAlternateFileName: [CHAR; 14],
+}
+
 impl Default for WIN32_FILE_ATTRIBUTE_DATA {
     fn default() -> WIN32_FILE_ATTRIBUTE_DATA {
         WIN32_FILE_ATTRIBUTE_DATA {
@@ -580,6 +595,9 @@ extern "system" {
                        hTemplateFile: HANDLE)
                        -> HANDLE;
     pub fn GetFileSizeEx(hFile: HANDLE, lpFileSize: *mut i64) -> BOOL;
+    pub fn FindFirstFileA(lpFileName: LPCTSTR, lpFindFileData: *mut WIN32_FIND_DATAA) -> HANDLE;
+    pub fn FindNextFileA(hFindFile: HANDLE, lpFindFileData: *mut WIN32_FIND_DATAA) -> BOOL;
+    pub fn FindClose(hFindFile: HANDLE) -> BOOL;
     pub fn Sleep(dwMilliseconds: DWORD);
     pub fn CopyFileA(lpExistingFileName: LPCTSTR,
                      lpNewFileName: LPCTSTR,
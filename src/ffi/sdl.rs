@@ -3,15 +3,20 @@
 
 pub use libc::{c_int, c_char, c_void};
 use std::default::Default;
+use std::ffi::{CStr, CString};
 use std::ptr;
 
 pub type SDL_Keycode = i32;
+// Physical key position, named after the key on a US layout
+pub type SDL_Scancode = c_int;
 pub type SDL_AudioFormat = u16;
 pub type SDLAudioCallbackT = extern "C" fn(*mut c_void, *mut u8, c_int);
 pub type SDL_Window = c_void;
 pub type SDL_Renderer = c_void;
 pub type SDL_GameController = c_void;
 pub type SDL_Texture = c_void;
+pub type SDL_Joystick = c_void;
+pub type SDL_JoystickID = i32;
 
 pub const AUDIO_S16LSB: u16 = 0x8010;
 
@@ -35,15 +40,52 @@ pub const SDL_PIXELFORMAT_RGBA8888: u32 = 0x16462004;
 pub const SDL_TEXTUREACCESS_STREAMING: c_int = 1;
 
 pub const SDL_WINDOW_RESIZABLE: u32 = 0x00000020;
+pub const SDL_WINDOW_FULLSCREEN: u32 = 0x00000001;
+pub const SDL_WINDOW_FULLSCREEN_DESKTOP: u32 = SDL_WINDOW_FULLSCREEN | 0x00001000;
 
 pub const SDL_QUIT: u32 = 0x100;
 pub const SDL_WINDOWEVENT: u32 = 0x200;
 pub const SDL_KEYDOWN: u32 = 0x300;
 pub const SDL_KEYUP: u32 = 0x301;
+pub const SDL_MOUSEMOTION: u32 = 0x400;
+pub const SDL_MOUSEBUTTONDOWN: u32 = 0x401;
+pub const SDL_MOUSEBUTTONUP: u32 = 0x402;
+pub const SDL_MOUSEWHEEL: u32 = 0x403;
+pub const SDL_CONTROLLERDEVICEADDED: u32 = 0x653;
+pub const SDL_CONTROLLERDEVICEREMOVED: u32 = 0x654;
 
 pub const SDL_PRESSED: u8 = 1;
-
-
+pub const SDL_RELEASED: u8 = 0;
+
+// Virtual joysticks (SDL 2.0.14) stand in for real pads in the tests
+pub const SDL_JOYSTICK_TYPE_GAMECONTROLLER: c_int = 1;
+
+pub const SDL_BUTTON_LEFT: u8 = 1;
+pub const SDL_BUTTON_MIDDLE: u8 = 2;
+pub const SDL_BUTTON_RIGHT: u8 = 3;
+pub const SDL_BUTTON_X1: u8 = 4;
+pub const SDL_BUTTON_X2: u8 = 5;
+
+
+pub const SDL_SCANCODE_UNKNOWN: SDL_Scancode = 0;
+pub const SDL_SCANCODE_A: SDL_Scancode = 4;
+pub const SDL_SCANCODE_D: SDL_Scancode = 7;
+pub const SDL_SCANCODE_E: SDL_Scancode = 8;
+pub const SDL_SCANCODE_L: SDL_Scancode = 15;
+pub const SDL_SCANCODE_Q: SDL_Scancode = 20;
+pub const SDL_SCANCODE_S: SDL_Scancode = 22;
+pub const SDL_SCANCODE_W: SDL_Scancode = 26;
+pub const SDL_SCANCODE_RETURN: SDL_Scancode = 40;
+pub const SDL_SCANCODE_ESCAPE: SDL_Scancode = 41;
+pub const SDL_SCANCODE_SPACE: SDL_Scancode = 44;
+pub const SDL_SCANCODE_F4: SDL_Scancode = 61;
+pub const SDL_SCANCODE_F12: SDL_Scancode = 69;
+pub const SDL_SCANCODE_RIGHT: SDL_Scancode = 79;
+pub const SDL_SCANCODE_LEFT: SDL_Scancode = 80;
+pub const SDL_SCANCODE_DOWN: SDL_Scancode = 81;
+pub const SDL_SCANCODE_UP: SDL_Scancode = 82;
+
+pub const SDLK_UNKNOWN: i32 = 0;
 pub const SDLK_w: i32 = 'w' as i32;
 pub const SDLK_a: i32 = 'a' as i32;
 pub const SDLK_s: i32 = 's' as i32;
@@ -52,9 +94,11 @@ pub const SDLK_e: i32 = 'e' as i32;
 pub const SDLK_p: i32 = 'p' as i32;
 pub const SDLK_q: i32 = 'q' as i32;
 pub const SDLK_l: i32 = 'l' as i32;
+pub const SDLK_RETURN: i32 = 13;
 pub const SDLK_ESCAPE: i32 = 27;
 pub const SDLK_SPACE: i32 = 0x20 as i32;
 pub const SDLK_F4: i32 = (1 << 30) | 61;
+pub const SDLK_F12: i32 = (1 << 30) | 69;
 pub const SDLK_RIGHT: i32 = (1 << 30) | 79;
 pub const SDLK_LEFT: i32 = (1 << 30) | 80;
 pub const SDLK_DOWN: i32 = (1 << 30) | 81;
@@ -129,6 +173,12 @@ pub struct SDL_AudioSpec {
     pub userdata: *mut c_void,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SDL_JoystickGUID {
+    pub data: [u8; 16],
+}
+
 #[repr(C)]
 pub enum SDL_GameControllerAxis {
     SDL_CONTROLLER_AXIS_INVALID = -1,
@@ -142,6 +192,7 @@ pub enum SDL_GameControllerAxis {
 }
 
 #[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub enum SDL_GameControllerButton {
     SDL_CONTROLLER_BUTTON_INVALID = -1,
     SDL_CONTROLLER_BUTTON_A,
@@ -162,11 +213,28 @@ pub enum SDL_GameControllerButton {
     SDL_CONTROLLER_BUTTON_MAX,
 }
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub enum SDL_Scancode {
-    SDL_SCANCODE_UNKNOWN = 0,
-    SDL_SCANCODE_MAX = 512,
+impl SDL_GameControllerButton {
+    pub fn from_raw(value: c_int) -> Option<SDL_GameControllerButton> {
+        use self::SDL_GameControllerButton::*;
+        match value {
+            0 => Some(SDL_CONTROLLER_BUTTON_A),
+            1 => Some(SDL_CONTROLLER_BUTTON_B),
+            2 => Some(SDL_CONTROLLER_BUTTON_X),
+            3 => Some(SDL_CONTROLLER_BUTTON_Y),
+            4 => Some(SDL_CONTROLLER_BUTTON_BACK),
+            5 => Some(SDL_CONTROLLER_BUTTON_GUIDE),
+            6 => Some(SDL_CONTROLLER_BUTTON_START),
+            7 => Some(SDL_CONTROLLER_BUTTON_LEFTSTICK),
+            8 => Some(SDL_CONTROLLER_BUTTON_RIGHTSTICK),
+            9 => Some(SDL_CONTROLLER_BUTTON_LEFTSHOULDER),
+            10 => Some(SDL_CONTROLLER_BUTTON_RIGHTSHOULDER),
+            11 => Some(SDL_CONTROLLER_BUTTON_DPAD_UP),
+            12 => Some(SDL_CONTROLLER_BUTTON_DPAD_DOWN),
+            13 => Some(SDL_CONTROLLER_BUTTON_DPAD_LEFT),
+            14 => Some(SDL_CONTROLLER_BUTTON_DPAD_RIGHT),
+            _ => None,
+        }
+    }
 }
 
 
@@ -192,6 +260,55 @@ pub struct SDL_KeyboardEvent {
     pub keysym: SDL_Keysym,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SDL_MouseMotionEvent {
+    pub _type: u32,
+    pub timestamp: u32,
+    pub windowID: u32,
+    pub which: u32,
+    pub state: u32,
+    pub x: i32,
+    pub y: i32,
+    pub xrel: i32,
+    pub yrel: i32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SDL_MouseButtonEvent {
+    pub _type: u32,
+    pub timestamp: u32,
+    pub windowID: u32,
+    pub which: u32,
+    pub button: u8,
+    pub state: u8,
+    pub clicks: u8,
+    pub padding1: u8,
+    pub x: i32,
+    pub y: i32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SDL_MouseWheelEvent {
+    pub _type: u32,
+    pub timestamp: u32,
+    pub windowID: u32,
+    pub which: u32,
+    pub x: i32,
+    pub y: i32,
+    pub direction: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SDL_ControllerDeviceEvent {
+    pub _type: u32,
+    pub timestamp: u32,
+    pub which: i32,
+}
+
 #[repr(C)]
 pub struct SDL_Quit {
     pub _type: u32,
@@ -229,10 +346,39 @@ impl SDL_Event {
     pub fn keyboard_event(&self) -> SDL_KeyboardEvent {
         unsafe { *(self.data.as_ptr() as *const _) }
     }
+
+    pub fn mouse_motion_event(&self) -> SDL_MouseMotionEvent {
+        unsafe { *(self.data.as_ptr() as *const _) }
+    }
+
+    pub fn mouse_button_event(&self) -> SDL_MouseButtonEvent {
+        unsafe { *(self.data.as_ptr() as *const _) }
+    }
+
+    pub fn mouse_wheel_event(&self) -> SDL_MouseWheelEvent {
+        unsafe { *(self.data.as_ptr() as *const _) }
+    }
+
+    pub fn controller_device_event(&self) -> SDL_ControllerDeviceEvent {
+        unsafe { *(self.data.as_ptr() as *const _) }
+    }
+}
+
+pub fn sdl_error() -> Option<CString> {
+    unsafe {
+        let val = SDL_GetError();
+        if val.is_null() || *val == 0 {
+            None
+        } else {
+            Some(CString::new(CStr::from_ptr(val).to_bytes()).unwrap())
+        }
+    }
 }
 
 extern "C" {
+    fn SDL_GetError() -> *const c_char;
     pub fn SDL_Init(flags: u32) -> c_int;
+    pub fn SDL_InitSubSystem(flags: u32) -> c_int;
     pub fn SDL_Quit();
     pub fn SDL_CreateWindow(title: *const c_char,
                             x: c_int,
@@ -264,6 +410,12 @@ extern "C" {
                              h: c_int)
                              -> *mut SDL_Texture;
     pub fn SDL_GetWindowSize(window: *mut SDL_Window, w: *mut c_int, h: *mut c_int);
+    pub fn SDL_GetWindowFlags(window: *mut SDL_Window) -> u32;
+    pub fn SDL_SetWindowFullscreen(window: *mut SDL_Window, flags: u32) -> c_int;
+    pub fn SDL_GetRendererOutputSize(renderer: *mut SDL_Renderer,
+                                     w: *mut c_int,
+                                     h: *mut c_int)
+                                     -> c_int;
     pub fn SDL_UpdateTexture(texture: *mut SDL_Texture,
                              rect: *const SDL_Rect,
                              pixels: *const c_void,
@@ -280,14 +432,39 @@ extern "C" {
     pub fn SDL_GameControllerOpen(joystick_index: c_int) -> *mut SDL_GameController;
     pub fn SDL_GameControllerClose(game_controller: *mut SDL_GameController);
     pub fn SDL_GameControllerGetAttached(game_controller: *mut SDL_GameController) -> SDL_bool;
+    pub fn SDL_GameControllerGetJoystick(game_controller: *mut SDL_GameController)
+                                         -> *mut SDL_Joystick;
+    pub fn SDL_GameControllerGetButtonFromString(pchString: *const c_char) -> c_int;
+    pub fn SDL_GetKeyFromName(name: *const c_char) -> SDL_Keycode;
+    pub fn SDL_GetScancodeFromName(name: *const c_char) -> SDL_Scancode;
+    pub fn SDL_JoystickInstanceID(joystick: *mut SDL_Joystick) -> SDL_JoystickID;
     pub fn SDL_GameControllerGetButton(game_controller: *mut SDL_GameController,
                                        button: SDL_GameControllerButton)
                                        -> u8;
     pub fn SDL_GameControllerGetAxis(game_controller: *mut SDL_GameController,
                                      axis: SDL_GameControllerAxis)
                                      -> i16;
+    pub fn SDL_GameControllerUpdate();
+    pub fn SDL_GameControllerAddMapping(mapping_string: *const c_char) -> c_int;
+    pub fn SDL_JoystickGetDeviceGUID(device_index: c_int) -> SDL_JoystickGUID;
+    pub fn SDL_JoystickGetGUIDString(guid: SDL_JoystickGUID, psz_guid: *mut c_char, cb_guid: c_int);
+    pub fn SDL_JoystickAttachVirtual(joystick_type: c_int,
+                                     naxes: c_int,
+                                     nbuttons: c_int,
+                                     nhats: c_int)
+                                     -> c_int;
+    pub fn SDL_JoystickDetachVirtual(device_index: c_int) -> c_int;
+    pub fn SDL_JoystickSetVirtualAxis(joystick: *mut SDL_Joystick,
+                                      axis: c_int,
+                                      value: i16)
+                                      -> c_int;
+    pub fn SDL_JoystickSetVirtualButton(joystick: *mut SDL_Joystick,
+                                        button: c_int,
+                                        value: u8)
+                                        -> c_int;
     pub fn SDL_OpenAudio(desired: *mut SDL_AudioSpec, obtained: *mut SDL_AudioSpec) -> c_int;
     pub fn SDL_PauseAudio(pause_on: c_int);
+    pub fn SDL_CloseAudio();
     pub fn SDL_GetWindowDisplayIndex(window: *mut SDL_Window) -> c_int;
     pub fn SDL_GetDesktopDisplayMode(display: c_int, mode: *mut SDL_DisplayMode) -> c_int;
     pub fn SDL_GetPerformanceCounter() -> u64;
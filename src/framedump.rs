@@ -0,0 +1,163 @@
+// Frame dumps
+//
+// Writes what the software renderer produced to numbered 32 bit BMP files,
+// either a single frame on request or every frame of an input playback.
+// The files are bottom-up BI_BITFIELDS bitmaps with explicit channel masks,
+// the same flavour debug_load_bitmap reads, so dumped frames can be compared
+// against golden images by the game code as well as by external tools.
+//
+// While input gets recorded, a checksum of every rendered frame is written
+// next to the recording. A dump playback compares its frames against them,
+// so it's visible right away when the playback doesn't reproduce the
+// recorded session.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+const FILE_HEADER_SIZE: u32 = 14;
+const INFO_HEADER_SIZE: u32 = 40;
+const MASKS_SIZE: u32 = 12;
+const BI_BITFIELDS: u32 = 3;
+const BITS_PER_PIXEL: u16 = 32;
+
+pub struct FrameDumper {
+    dir: PathBuf,
+    next_index: u32,
+    capture_next: bool,
+    dump_playback: bool,
+}
+
+impl FrameDumper {
+    pub fn new(dir: PathBuf, dump_playback: bool) -> FrameDumper {
+        FrameDumper {
+            dir: dir,
+            next_index: 0,
+            capture_next: false,
+            dump_playback: dump_playback,
+        }
+    }
+
+    pub fn dumps_playback(&self) -> bool {
+        self.dump_playback
+    }
+
+    // The frame that is rendered next gets written out
+    pub fn request_capture(&mut self) {
+        self.capture_next = true;
+    }
+
+    // playback_frame is the index of the frame in the input recording if one
+    // is being replayed right now. Playback frames are named after that
+    // index so the dumps of two runs line up file by file.
+    pub fn end_frame(&mut self,
+                     pixels: &[u32],
+                     width: usize,
+                     height: usize,
+                     pitch: usize,
+                     playback_frame: Option<u32>) {
+        let name = match playback_frame {
+            Some(frame) if self.dump_playback => format!("playback_{:06}.bmp", frame),
+            _ if self.capture_next => {
+                self.next_index += 1;
+                format!("frame_{:06}.bmp", self.next_index)
+            }
+            _ => return,
+        };
+        self.capture_next = false;
+
+        let mut path = self.dir.clone();
+        if let Err(error) = fs::create_dir_all(&path) {
+            println!("The frame dump directory could not be created! ({:?}: {})",
+                     path,
+                     error);
+            return;
+        }
+        path.push(name);
+
+        match File::create(&path)
+                  .and_then(|file| write_bmp(BufWriter::new(file), pixels, width, height, pitch)) {
+            Ok(()) => {
+                if playback_frame.is_none() {
+                    println!("Dumped frame to {:?}", path);
+                }
+            }
+            Err(error) => println!("The frame could not be dumped! ({:?}: {})", path, error),
+        }
+    }
+}
+
+// pixels are 0xAARRGGBB like the VideoBuffer
+pub fn write_bmp<W: Write>(mut out: W,
+                           pixels: &[u32],
+                           width: usize,
+                           height: usize,
+                           pitch: usize)
+                           -> io::Result<()> {
+    let image_size = (width * height * 4) as u32;
+    let offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE + MASKS_SIZE;
+
+    // BITMAPFILEHEADER
+    out.write_all(b"BM")?;
+    out.write_all(&(offset + image_size).to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&offset.to_le_bytes())?;
+
+    // BITMAPINFOHEADER
+    out.write_all(&INFO_HEADER_SIZE.to_le_bytes())?;
+    out.write_all(&(width as i32).to_le_bytes())?;
+    out.write_all(&(height as i32).to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&BITS_PER_PIXEL.to_le_bytes())?;
+    out.write_all(&BI_BITFIELDS.to_le_bytes())?;
+    out.write_all(&image_size.to_le_bytes())?;
+    // 72 dpi
+    out.write_all(&2835i32.to_le_bytes())?;
+    out.write_all(&2835i32.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+
+    // Red, green and blue masks, alpha is whatever is left over
+    out.write_all(&0x00ff0000u32.to_le_bytes())?;
+    out.write_all(&0x0000ff00u32.to_le_bytes())?;
+    out.write_all(&0x000000ffu32.to_le_bytes())?;
+
+    // Bottom-up, the last row of the buffer comes first
+    for row in (0..height).rev() {
+        for pixel in &pixels[row * pitch..row * pitch + width] {
+            out.write_all(&pixel.to_le_bytes())?;
+        }
+    }
+
+    out.flush()
+}
+
+// FNV-1a over the visible pixels, the pitch doesn't change the sum
+pub fn frame_checksum(pixels: &[u32], width: usize, height: usize, pitch: usize) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for row in 0..height {
+        for pixel in &pixels[row * pitch..row * pitch + width] {
+            hash = (hash ^ *pixel as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+pub fn write_checksum<W: Write>(out: &mut W, checksum: u64) -> io::Result<()> {
+    out.write_all(&checksum.to_le_bytes())
+}
+
+// A trailing partial checksum from a session that died while writing is
+// dropped
+pub fn read_checksums(path: &Path) -> io::Result<Vec<u64>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes.chunks(8)
+            .filter(|chunk| chunk.len() == 8)
+            .map(|chunk| {
+                let mut sum = [0; 8];
+                sum.copy_from_slice(chunk);
+                u64::from_le_bytes(sum)
+            })
+            .collect())
+}
@@ -1,5 +1,5 @@
-use common::{VideoBuffer, ThreadContext};
-use common::PlatformReadEntireFileT;
+use common::{VideoBuffer, ThreadContext, GameMemory, LogLevel};
+use std::cmp;
 use std::slice;
 
 use super::math::V2;
@@ -15,9 +15,8 @@ pub struct Color {
 pub fn draw_rect(buffer: &mut VideoBuffer,
                  real_min: V2<f32>,
                  real_max: V2<f32>,
-                 r: f32,
-                 g: f32,
-                 b: f32) {
+                 color: Color,
+                 gamma_correct: bool) {
 
     let mut min_x = real_min.x.round() as isize;
     let mut max_x = real_max.x.round() as isize;
@@ -48,90 +47,291 @@ pub fn draw_rect(buffer: &mut VideoBuffer,
         0
     };
 
-    // Bit pattern: AA RR GG BB
-    let color: u32 = (((r * 255.0).round() as u32) << 16) | (((g * 255.0).round() as u32) << 8) |
-                     (b * 255.0).round() as u32;
+    let source = premultiply(color, gamma_correct);
+    let opaque = color.a >= 1.0;
+    let color32 = from_blend_space(source, gamma_correct);
 
     for row in buffer.memory.chunks_mut(buffer.pitch).skip(min_y as usize).take(height) {
         for pixel in row.iter_mut().skip(min_x as usize).take(width) {
-            *pixel = color;
+            *pixel = if opaque {
+                color32
+            } else {
+                let dest = to_blend_space(*pixel, gamma_correct);
+                from_blend_space(blend_over(source, dest), gamma_correct)
+            };
         }
     }
 }
 
-// TODO: see how to do this crazy blit with iterators to be more idiomatic rust!
-// Something with iter.zip!
-pub fn draw_bitmap_alpha(buffer: &mut VideoBuffer,
-                         bitmap: &Bitmap,
-                         top_left: V2<f32>,
-                         alpha: f32) {
-
-    let mut min_y = top_left.y.round() as isize;
-    let mut min_x = top_left.x.round() as isize;
-    let mut max_x = min_x + bitmap.width as isize;
-    let mut max_y = min_y + bitmap.height as isize;
-
-    let mut source_offset_x = 0;
-    if min_x < 0 {
-        source_offset_x -= min_x;
-        min_x = 0;
-    }
-    let mut source_offset_y = 0;
-    if min_y < 0 {
-        source_offset_y -= min_y;
-        min_y = 0;
+// Sets every pixel to color, unlike draw_rect a translucent color replaces
+// what was there
+pub fn clear(buffer: &mut VideoBuffer, color: Color, gamma_correct: bool) {
+    let color32 = from_blend_space(premultiply(color, gamma_correct), gamma_correct);
+    for row in buffer.memory.chunks_mut(buffer.pitch) {
+        for pixel in row.iter_mut().take(buffer.width) {
+            *pixel = color32;
+        }
     }
-    if max_x > buffer.width as isize {
-        max_x = buffer.width as isize;
+}
+
+// Pixels are stored with premultiplied alpha. Blending happens on 0-1
+// values, with gamma correction the colors get converted to linear light
+// first and back to sRGB afterwards. Squaring and taking the square root
+// stand in for the real sRGB curve.
+//
+// Bitmaps get premultiplied once when they are loaded, for each blend space
+// separately. Drawing picks the copy that matches.
+
+// Components in 0-255
+fn unpack_color(pixel: u32) -> Color {
+    Color {
+        r: ((pixel >> 16) & 0xFF) as f32,
+        g: ((pixel >> 8) & 0xFF) as f32,
+        b: (pixel & 0xFF) as f32,
+        a: (pixel >> 24) as f32,
     }
-    if max_y > buffer.height as isize {
-        max_y = buffer.height as isize;
+}
+
+fn pack_color(color: Color) -> u32 {
+    // Bit pattern: AA RR GG BB
+    ((color.a.min(255.0).round() as u32) << 24) | ((color.r.min(255.0).round() as u32) << 16) |
+    ((color.g.min(255.0).round() as u32) << 8) | color.b.min(255.0).round() as u32
+}
+
+fn to_blend_space(pixel: u32, gamma_correct: bool) -> Color {
+    let color = scale_color(unpack_color(pixel), 1.0 / 255.0);
+    if gamma_correct {
+        Color {
+            r: color.r * color.r,
+            g: color.g * color.g,
+            b: color.b * color.b,
+            a: color.a,
+        }
+    } else {
+        color
     }
+}
 
-    let bitmap_offset = bitmap.width as isize * (bitmap.height as isize - 1) -
-                        bitmap.width as isize * source_offset_y +
-                        source_offset_x;
-    let buffer_offset = min_y * buffer.pitch as isize + min_x;
+fn from_blend_space(color: Color, gamma_correct: bool) -> u32 {
+    let color = if gamma_correct {
+        Color {
+            r: color.r.sqrt(),
+            g: color.g.sqrt(),
+            b: color.b.sqrt(),
+            a: color.a,
+        }
+    } else {
+        color
+    };
+    pack_color(scale_color(color, 255.0))
+}
 
-    let mut source_row = unsafe { bitmap.memory.as_ptr().offset(bitmap_offset) };
-    let mut dest_row = unsafe { buffer.memory.as_mut_ptr().offset(buffer_offset) };
-    for _ in min_y..max_y {
-        let mut dest = dest_row;
-        let mut source = source_row;
+// From a straight sRGB color to premultiplied blend space
+fn premultiply(color: Color, gamma_correct: bool) -> Color {
+    let (r, g, b) = if gamma_correct {
+        (color.r * color.r, color.g * color.g, color.b * color.b)
+    } else {
+        (color.r, color.g, color.b)
+    };
+    Color {
+        r: r * color.a,
+        g: g * color.a,
+        b: b * color.a,
+        a: color.a,
+    }
+}
 
-        for _ in min_x..max_x {
-            unsafe {
-                let a = ((*source >> 24) as f32 / 255.0) * alpha;
-                let sr = (*source >> 16) & 0xFF;
-                let sg = (*source >> 8) & 0xFF;
-                let sb = *source & 0xFF;
+fn scale_color(color: Color, factor: f32) -> Color {
+    Color {
+        r: color.r * factor,
+        g: color.g * factor,
+        b: color.b * factor,
+        a: color.a * factor,
+    }
+}
+
+// Premultiplied "over", the alpha of dest is composited as well so drawing
+// into an offscreen bitmap leaves something that can be drawn again
+fn blend_over(source: Color, dest: Color) -> Color {
+    let inv_a = 1.0 - source.a;
+    Color {
+        r: source.r + inv_a * dest.r,
+        g: source.g + inv_a * dest.g,
+        b: source.b + inv_a * dest.b,
+        a: source.a + inv_a * dest.a,
+    }
+}
 
-                let dr = (*dest >> 16) & 0xFF;
-                let dg = (*dest >> 8) & 0xFF;
-                let db = *dest & 0xFF;
+// Pixel bounding box of a parallelogram, clamped to the buffer
+fn parallelogram_bounds(buffer: &VideoBuffer,
+                        origin: V2<f32>,
+                        x_axis: V2<f32>,
+                        y_axis: V2<f32>)
+                        -> (isize, isize, isize, isize) {
+    let corners = [origin, origin + x_axis, origin + y_axis, origin + x_axis + y_axis];
+    let mut min_x = buffer.width as isize;
+    let mut min_y = buffer.height as isize;
+    let mut max_x = 0;
+    let mut max_y = 0;
+    for corner in &corners {
+        min_x = cmp::min(min_x, corner.x.floor() as isize);
+        min_y = cmp::min(min_y, corner.y.floor() as isize);
+        max_x = cmp::max(max_x, corner.x.ceil() as isize);
+        max_y = cmp::max(max_y, corner.y.ceil() as isize);
+    }
+    (cmp::max(min_x, 0),
+     cmp::max(min_y, 0),
+     cmp::min(max_x, buffer.width as isize),
+     cmp::min(max_y, buffer.height as isize))
+}
 
-                // Lerp between source and dest
-                let r = ((1.0 - a) * dr as f32 + a * sr as f32).round() as u32;
-                let g = ((1.0 - a) * dg as f32 + a * sg as f32).round() as u32;
-                let b = ((1.0 - a) * db as f32 + a * sb as f32).round() as u32;
+fn lerp_color(a: Color, t: f32, b: Color) -> Color {
+    Color {
+        r: (1.0 - t) * a.r + t * b.r,
+        g: (1.0 - t) * a.g + t * b.g,
+        b: (1.0 - t) * a.b + t * b.b,
+        a: (1.0 - t) * a.a + t * b.a,
+    }
+}
 
-                *dest = (r << 16) | (g << 8) | b;
+// Bilinear sample at texel coordinates, y counts rows from the top. The
+// texels get filtered in blend space.
+fn sample_bilinear(bitmap: &Bitmap, x: f32, y: f32, gamma_correct: bool) -> Color {
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let fx = x - x0;
+    let fy = y - y0;
+
+    let max_x = bitmap.width as isize - 1;
+    let max_y = bitmap.height as isize - 1;
+    let x0 = cmp::min(cmp::max(x0 as isize, 0), max_x);
+    let y0 = cmp::min(cmp::max(y0 as isize, 0), max_y);
+    let x1 = cmp::min(x0 + 1, max_x);
+    let y1 = cmp::min(y0 + 1, max_y);
+
+    // Rows are stored bottom up
+    let texels = bitmap.texels(gamma_correct);
+    let texel = |x: isize, y: isize| {
+        let index = (max_y - y) * bitmap.width as isize + x;
+        to_blend_space(texels[index as usize], gamma_correct)
+    };
+    let top = lerp_color(texel(x0, y0), fx, texel(x1, y0));
+    let bottom = lerp_color(texel(x0, y1), fx, texel(x1, y1));
+    lerp_color(top, fy, bottom)
+}
 
-                source = source.offset(1);
-                dest = dest.offset(1);
+// Maps bitmap onto the parallelogram spanned by x_axis and y_axis from
+// origin, all in pixels. The top left of the bitmap lands on origin, x_axis
+// runs along its rows and y_axis down its columns. Positions are not
+// rounded and the bitmap is filtered bilinearly, so it can move by fractions
+// of a pixel, scale and rotate. Every texel gets multiplied by tint.
+pub fn draw_bitmap_slowly(buffer: &mut VideoBuffer,
+                          origin: V2<f32>,
+                          x_axis: V2<f32>,
+                          y_axis: V2<f32>,
+                          bitmap: &Bitmap,
+                          tint: Color,
+                          gamma_correct: bool) {
+
+    let det = x_axis.x * y_axis.y - x_axis.y * y_axis.x;
+    if det == 0.0 || bitmap.width == 0 || bitmap.height == 0 {
+        return;
+    }
+    let inv_det = 1.0 / det;
+    let (min_x, min_y, max_x, max_y) = parallelogram_bounds(buffer, origin, x_axis, y_axis);
+
+    let tint = premultiply(tint, gamma_correct);
+
+    // Texel centers at the edges land on the edges of the parallelogram
+    let texel_w = (bitmap.width - 1) as f32;
+    let texel_h = (bitmap.height - 1) as f32;
+
+    for y in min_y..max_y {
+        let row = y as usize * buffer.pitch;
+        for x in min_x..max_x {
+            let d = V2 {
+                x: x as f32 + 0.5,
+                y: y as f32 + 0.5,
+            } - origin;
+            let u = (d.x * y_axis.y - d.y * y_axis.x) * inv_det;
+            let v = (x_axis.x * d.y - x_axis.y * d.x) * inv_det;
+
+            if u >= 0.0 && u < 1.0 && v >= 0.0 && v < 1.0 {
+                let texel = sample_bilinear(bitmap, u * texel_w, v * texel_h, gamma_correct);
+                let source = Color {
+                    r: texel.r * tint.r,
+                    g: texel.g * tint.g,
+                    b: texel.b * tint.b,
+                    a: texel.a * tint.a,
+                };
+
+                let dest = &mut buffer.memory[row + x as usize];
+                let blended = blend_over(source, to_blend_space(*dest, gamma_correct));
+                *dest = from_blend_space(blended, gamma_correct);
             }
         }
+    }
+}
+
+// Blits bitmap with the top left corner of its first texel on top_left.
+// A position between pixels gets filtered, every pixel mixes the up to four
+// texels it overlaps, so slowly moving bitmaps don't crawl from pixel to
+// pixel.
+pub fn draw_bitmap_alpha(buffer: &mut VideoBuffer,
+                         bitmap: &Bitmap,
+                         top_left: V2<f32>,
+                         alpha: f32,
+                         gamma_correct: bool) {
+
+    let start_x = top_left.x.floor();
+    let start_y = top_left.y.floor();
+    let fx = top_left.x - start_x;
+    let fy = top_left.y - start_y;
+    let start_x = start_x as isize;
+    let start_y = start_y as isize;
+
+    // The bitmap spills into one more column and row unless it sits exactly
+    // on a pixel
+    let width = bitmap.width as isize;
+    let height = bitmap.height as isize;
+    let min_x = cmp::max(start_x, 0);
+    let min_y = cmp::max(start_y, 0);
+    let max_x = cmp::min(start_x + width + 1, buffer.width as isize);
+    let max_y = cmp::min(start_y + height + 1, buffer.height as isize);
+
+    // Transparent around the bitmap, rows are stored bottom up
+    let texels = bitmap.texels(gamma_correct);
+    let texel = |x: isize, y: isize| {
+        if x < 0 || y < 0 || x >= width || y >= height {
+            Color::default()
+        } else {
+            to_blend_space(texels[((height - 1 - y) * width + x) as usize], gamma_correct)
+        }
+    };
 
-        unsafe {
-            dest_row = dest_row.offset(buffer.pitch as isize);
-            source_row = source_row.offset(-(bitmap.width as isize));
+    for y in min_y..max_y {
+        let row = y as usize * buffer.pitch;
+        let texel_y = y - start_y;
+        for x in min_x..max_x {
+            // The pixel covers fx of the texel to its left and 1 - fx of
+            // the one it starts at, the same for the rows
+            let texel_x = x - start_x;
+            let upper = lerp_color(texel(texel_x, texel_y - 1),
+                                   fx,
+                                   texel(texel_x - 1, texel_y - 1));
+            let lower = lerp_color(texel(texel_x, texel_y), fx, texel(texel_x - 1, texel_y));
+            let source = scale_color(lerp_color(lower, fy, upper), alpha);
+
+            let dest = &mut buffer.memory[row + x as usize];
+            let blended = blend_over(source, to_blend_space(*dest, gamma_correct));
+            *dest = from_blend_space(blended, gamma_correct);
         }
     }
 }
 
 #[allow(dead_code)]
 pub fn draw_bitmap(buffer: &mut VideoBuffer, bitmap: &Bitmap, x: f32, y: f32) {
-    draw_bitmap_alpha(buffer, bitmap, V2 { x: x, y: y }, 1.0);
+    draw_bitmap_alpha(buffer, bitmap, V2 { x: x, y: y }, 1.0, true);
 }
 
 #[repr(C, packed)]
@@ -161,7 +361,42 @@ struct BitmapHeader {
 pub struct Bitmap<'a> {
     width: u32,
     height: u32,
+    // Premultiplied in sRGB, for blending without gamma correction
     memory: &'a [u32],
+    // Premultiplied in linear light and stored as sRGB again like the
+    // pixels of the buffer, for gamma correct blending
+    linear_memory: &'a [u32],
+}
+
+// A bitmap as it comes out of the file, its texels still have straight alpha
+pub struct LoadedBitmap {
+    width: u32,
+    height: u32,
+    texels: &'static mut [u32],
+}
+
+impl LoadedBitmap {
+    pub fn texel_count(&self) -> usize {
+        self.texels.len()
+    }
+}
+
+impl<'a> Bitmap<'a> {
+    fn texels(&self, gamma_correct: bool) -> &'a [u32] {
+        if gamma_correct {
+            self.linear_memory
+        } else {
+            self.memory
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
 }
 
 fn rotate_left(value: u32, mut amount: i32) -> u32 {
@@ -176,17 +411,26 @@ fn rotate_left(value: u32, mut amount: i32) -> u32 {
 #[cfg(feature = "internal")]
 // Note: This function only loads a specific fileformat and is not generic.
 // only bottom up AABBGGRR Bitmaps
-pub fn debug_load_bitmap(read_func: PlatformReadEntireFileT,
+pub fn debug_load_bitmap(game_memory: &GameMemory,
                          context: &ThreadContext,
                          file_name: &str)
-                         -> Option<Bitmap<'static>> {
+                         -> Option<LoadedBitmap> {
 
     // Note: Bitmap byteorder is determined by the header. bottom up
-    let file = read_func(context, file_name);
+    let file = (game_memory.platform_read_entire_file)(context, file_name);
     if let Ok(result) = file {
         let header: &BitmapHeader = unsafe { &*(result.contents as *const BitmapHeader) };
 
-        debug_assert_eq!(header.compression, 3);
+        if header.compression != 3 {
+            platform_log!(game_memory,
+                          context,
+                          LogLevel::Error,
+                          "{} is not a BI_BITFIELDS bitmap (compression {})",
+                          file_name,
+                          { header.compression });
+            (game_memory.platform_free_file_memory)(context, result.contents, result.size);
+            return None;
+        }
 
         let pixels = unsafe {
             slice::from_raw_parts_mut(
@@ -203,20 +447,58 @@ pub fn debug_load_bitmap(read_func: PlatformReadEntireFileT,
         let green_shift = 8 - green_mask.trailing_zeros() as i32;
         let blue_shift = 0 - blue_mask.trailing_zeros() as i32;
 
-        // Shift bits according to masks
+        // Shift bits according to masks, premultiply_bitmap takes it from
+        // there
         for pixel in pixels.iter_mut() {
             *pixel = rotate_left(*pixel & red_mask, red_shift) |
                      rotate_left(*pixel & green_mask, green_shift) |
                      rotate_left(*pixel & blue_mask, blue_shift) |
-                     rotate_left(*pixel & alpha_mask, alpha_shift)
+                     rotate_left(*pixel & alpha_mask, alpha_shift);
         }
 
-        Some(Bitmap {
+        Some(LoadedBitmap {
             width: header.width as u32,
             height: header.height as u32,
-            memory: pixels,
+            texels: pixels,
         })
     } else {
+        platform_log!(game_memory,
+                      context,
+                      LogLevel::Error,
+                      "The bitmap {} could not be read",
+                      file_name);
         None
     }
 }
+
+// Stands in for bitmaps that could not be loaded so the game keeps running
+pub fn missing_bitmap() -> Bitmap<'static> {
+    static MISSING: [u32; 4] = [0xffff00ff, 0xff000000, 0xff000000, 0xffff00ff];
+    // Opaque, so the same in both blend spaces
+    Bitmap {
+        width: 2,
+        height: 2,
+        memory: &MISSING,
+        linear_memory: &MISSING,
+    }
+}
+
+// Premultiplies the texels for both blend spaces, everything that draws
+// bitmaps expects them premultiplied. The sRGB copy stays where the texels
+// are, the linear one goes to linear_memory, which needs room for
+// texel_count of them.
+pub fn premultiply_bitmap<'a>(loaded: LoadedBitmap, linear_memory: &'a mut [u32]) -> Bitmap<'a> {
+    debug_assert!(linear_memory.len() == loaded.texels.len());
+    for (texel, linear) in loaded.texels.iter_mut().zip(linear_memory.iter_mut()) {
+        let straight = scale_color(unpack_color(*texel), 1.0 / 255.0);
+        *linear = from_blend_space(premultiply(straight, true), true);
+        *texel = from_blend_space(premultiply(straight, false), false);
+    }
+
+    Bitmap {
+        width: loaded.width,
+        height: loaded.height,
+        memory: loaded.texels,
+        linear_memory: linear_memory,
+    }
+}
@@ -16,6 +16,11 @@ impl MemoryArena {
         }
     }
 
+    // Everything pushed so far is free again
+    pub fn clear(&mut self) {
+        self.used = 0;
+    }
+
     // TODO: Think about clear to zero options
     pub fn push_struct<'b, T>(&mut self) -> &'b mut T {
         let size = mem::size_of::<T>();
@@ -1,10 +1,9 @@
 use std::mem;
-use std::ptr;
 use std::default::Default;
 use std::f32::consts::PI;
 
 use common::{GameMemory, SoundBuffer, VideoBuffer, Input};
-use common::{ThreadContext, MAX_CONTROLLERS};
+use common::{ThreadContext, MAX_CONTROLLERS, GameAbi, GAME_ABI_VERSION};
 
 mod graphics;
 mod world;
@@ -13,17 +12,138 @@ mod random;
 mod math;
 mod simulation;
 mod entity;
+mod render_group;
 
 use self::world::World;
 use self::world::{WorldPosition, world_pos_from_tile};
 use self::memory::MemoryArena;
 use self::graphics::Color;
-use self::math::{V2, Rect};
+use self::math::{V2, V3, Rect};
+use self::render_group::{RenderGroup, RenderBasis, RenderLayer};
 use self::simulation::{EntityFlags};
 use self::simulation::{SimEntity, SimRegion, EntityReference};
 
 
+// One bitmap for a worker thread of the low priority queue. The file gets
+// read in a first round, premultiplied into bitmap in a second one.
+struct LoadBitmapWork<'a, 'm> {
+    game_memory: *const GameMemory<'m>,
+    file_name: &'static str,
+    bitmap: *mut graphics::Bitmap<'a>,
+    loaded: Option<graphics::LoadedBitmap>,
+    linear_memory: &'a mut [u32],
+}
+
+fn load_bitmap_work(context: &ThreadContext, data: *mut u8) {
+    let work = unsafe { &mut *(data as *mut LoadBitmapWork) };
+    work.loaded = graphics::debug_load_bitmap(unsafe { &*work.game_memory },
+                                              context,
+                                              work.file_name);
+}
+
+fn premultiply_bitmap_work(_context: &ThreadContext, data: *mut u8) {
+    let work = unsafe { &mut *(data as *mut LoadBitmapWork) };
+    if let Some(loaded) = work.loaded.take() {
+        let linear_memory = mem::replace(&mut work.linear_memory, &mut []);
+        unsafe {
+            *work.bitmap = graphics::premultiply_bitmap(loaded, linear_memory);
+        }
+    }
+}
+
+// work has to stay where it is until the low priority queue is done with it
+fn queue_bitmap_load<'a, 'm>(game_memory: &GameMemory<'m>,
+                             work: &mut LoadBitmapWork<'a, 'm>,
+                             bitmap: &mut graphics::Bitmap<'a>,
+                             file_name: &'static str) {
+    *work = LoadBitmapWork {
+        game_memory: game_memory,
+        file_name: file_name,
+        bitmap: bitmap,
+        loaded: None,
+        linear_memory: &mut [],
+    };
+    (game_memory.platform_add_work_entry)(game_memory.low_priority_queue,
+                                          load_bitmap_work,
+                                          work as *mut LoadBitmapWork as *mut u8);
+}
+
+// Everything the game state holds that lives outside of the game memory.
+// The files get read and converted on the low priority queue, this thread
+// helps out until all of them are done. The work entries only live in the
+// transient memory until then, the linear copies of the texels go to the
+// asset arena, which starts over on every load.
+fn load_assets(assets: &mut Assets,
+               asset_arena: &mut MemoryArena,
+               game_memory: &GameMemory,
+               context: &ThreadContext) {
+    let hero_files = [["test/test_hero_right_head.bmp",
+                       "test/test_hero_right_torso.bmp",
+                       "test/test_hero_right_cape.bmp"],
+                      ["test/test_hero_back_head.bmp",
+                       "test/test_hero_back_torso.bmp",
+                       "test/test_hero_back_cape.bmp"],
+                      ["test/test_hero_left_head.bmp",
+                       "test/test_hero_left_torso.bmp",
+                       "test/test_hero_left_cape.bmp"],
+                      ["test/test_hero_front_head.bmp",
+                       "test/test_hero_front_torso.bmp",
+                       "test/test_hero_front_cape.bmp"]];
+
+    let mut transient_arena = MemoryArena::new(game_memory.transient.len(),
+                                               game_memory.transient.as_ptr());
+    let work: &mut [LoadBitmapWork] = transient_arena.push_slice(4 + 3 * hero_files.len());
+    {
+        let mut entries = work.iter_mut();
+        let mut queue = |bitmap, file_name| {
+            queue_bitmap_load(game_memory, entries.next().unwrap(), bitmap, file_name)
+        };
+
+        queue(&mut assets.background_bitmap, "test/test_background.bmp");
+        queue(&mut assets.tree, "test2/tree00.bmp");
+        queue(&mut assets.shadow, "test/test_hero_shadow.bmp");
+        queue(&mut assets.sword, "test2/rock03.bmp");
+
+        for (hero_bitmaps, files) in assets.hero_bitmaps.iter_mut().zip(hero_files.iter()) {
+            queue(&mut hero_bitmaps.head, files[0]);
+            queue(&mut hero_bitmaps.torso, files[1]);
+            queue(&mut hero_bitmaps.cape, files[2]);
+            hero_bitmaps.align = V2 { x: 72, y: 182 };
+        }
+    }
+    (game_memory.platform_complete_all_work)(context, game_memory.low_priority_queue);
+
+    asset_arena.clear();
+    for entry in work.iter_mut() {
+        let texel_count = match entry.loaded {
+            Some(ref loaded) => loaded.texel_count(),
+            None => {
+                // Missing or broken bitmaps got logged by the loader and
+                // are replaced so a bad asset doesn't take the whole game
+                // down
+                unsafe {
+                    *entry.bitmap = graphics::missing_bitmap();
+                }
+                continue;
+            }
+        };
+        entry.linear_memory = asset_arena.push_slice(texel_count);
+        (game_memory.platform_add_work_entry)(game_memory.low_priority_queue,
+                                              premultiply_bitmap_work,
+                                              entry as *mut LoadBitmapWork as *mut u8);
+    }
+    (game_memory.platform_complete_all_work)(context, game_memory.low_priority_queue);
+}
+
 // ============= The public interface ===============
+#[no_mangle]
+pub extern "C" fn get_game_abi() -> GameAbi {
+    GameAbi {
+        version: GAME_ABI_VERSION,
+        game_state_size: mem::size_of::<GameState>() as u64,
+    }
+}
+
 // Has to be very low latency!
 #[no_mangle]
 pub extern "C" fn get_sound_samples(_context: &ThreadContext,
@@ -47,96 +167,14 @@ pub extern "C" fn update_and_render(context: &ThreadContext,
     // screen
     let mut rand_index = 6;
 
-    if !game_memory.initialized {
-        state.background_bitmap =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_background.bmp")
-                .unwrap();
-
-        state.tree = graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                                 context,
-                                                 "test2/tree00.bmp")
-                         .unwrap();
-
-        state.shadow = graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                                   context,
-                                                   "test/test_hero_shadow.bmp")
-                           .unwrap();
-
-        state.sword = graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                                  context,
-                                                  "test2/rock03.bmp")
-                          .unwrap();
-        state.hero_bitmaps[0].head =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_right_head.bmp")
-                .unwrap();
-        state.hero_bitmaps[0].torso =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_right_torso.bmp")
-                .unwrap();
-        state.hero_bitmaps[0].cape =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_right_cape.bmp")
-                .unwrap();
-        state.hero_bitmaps[0].align = V2 { x: 72, y: 182 };
-
-        state.hero_bitmaps[1].head =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_back_head.bmp")
-                .unwrap();
-        state.hero_bitmaps[1].torso =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_back_torso.bmp")
-                .unwrap();
-        state.hero_bitmaps[1].cape =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_back_cape.bmp")
-                .unwrap();
-        state.hero_bitmaps[1].align = V2 { x: 72, y: 182 };
-
-        state.hero_bitmaps[2].head =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_left_head.bmp")
-                .unwrap();
-        state.hero_bitmaps[2].torso =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_left_torso.bmp")
-                .unwrap();
-        state.hero_bitmaps[2].cape =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_left_cape.bmp")
-                .unwrap();
-        state.hero_bitmaps[2].align = V2 { x: 72, y: 182 };
-
-        state.hero_bitmaps[3].head =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_front_head.bmp")
-                .unwrap();
-        state.hero_bitmaps[3].torso =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_front_torso.bmp")
-                .unwrap();
-        state.hero_bitmaps[3].cape =
-            graphics::debug_load_bitmap(game_memory.platform_read_entire_file,
-                                        context,
-                                        "test/test_hero_front_cape.bmp")
-                .unwrap();
-        state.hero_bitmaps[3].align = V2 { x: 72, y: 182 };
-
+    // The bitmaps of a restored crash dump point into the memory of the
+    // session that crashed
+    if game_memory.initialized && game_memory.restored {
+        load_assets(&mut state.assets, &mut state.asset_arena, game_memory, context);
+        game_memory.restored = false;
+    }
 
+    if !game_memory.initialized {
         let game_state_size = mem::size_of::<GameState>();
         state.world_arena = MemoryArena::new(game_memory.permanent.len() - game_state_size,
                                              unsafe {
@@ -145,12 +183,17 @@ pub extern "C" fn update_and_render(context: &ThreadContext,
                                                             .offset(game_state_size as isize)
                                              });
 
+        let asset_memory: &mut [u8] = state.world_arena.push_slice(ASSET_MEMORY_SIZE);
+        state.asset_arena = MemoryArena::new(asset_memory.len(), asset_memory.as_ptr());
+        load_assets(&mut state.assets, &mut state.asset_arena, game_memory, context);
+
         state.world = state.world_arena.push_struct();
 
         state.world.initialize();
 
         let tile_side_pixels = 60;
         state.meters_to_pixel = tile_side_pixels as f32 / state.world.tile_side_meters;
+        state.gamma_correct = true;
 
         // Generating a random maze
         let tiles_per_screen_x = 17;
@@ -292,6 +335,11 @@ pub extern "C" fn update_and_render(context: &ThreadContext,
     let mut player_to_add = None;
     for (c_index, controller) in input.controllers.iter().enumerate() {
 
+        // Back switches gamma correct blending on and off for comparison
+        if controller.back.ended_down && controller.back.half_transitions > 0 {
+            state.gamma_correct = !state.gamma_correct;
+        }
+
         if let Some(controlled_hero) = state.controlled_heroes[c_index].as_mut() {
 
             // Zero it out so we get only movement from the last frame!
@@ -367,27 +415,30 @@ pub extern "C" fn update_and_render(context: &ThreadContext,
     let camera_bounds = Rect::center_dim(Default::default(),
                                          tiles_in_work_set * state.world.tile_side_meters);
 
-    let mut sim_arena = MemoryArena::new(game_memory.transient.len(),
-                                         game_memory.transient.as_ptr());
+    let mut transient_arena = MemoryArena::new(game_memory.transient.len(),
+                                               game_memory.transient.as_ptr());
+    let mut render_group = RenderGroup::new(&mut transient_arena,
+                                            MAX_RENDER_ENTRIES,
+                                            meters_to_pixel,
+                                            state.gamma_correct);
     let camera_pos = state.camera_position;
-    let sim_region = SimRegion::begin_sim(state, &mut sim_arena, camera_pos, camera_bounds);
+    let sim_region = SimRegion::begin_sim(state, &mut transient_arena, camera_pos, camera_bounds);
 
-    // Clear the screen to grey and start rendering
-    let buffer_dim = V2 {
-        x: video_buffer.width as f32,
-        y: video_buffer.height as f32,
-    };
-    graphics::draw_rect(video_buffer, Default::default(), buffer_dim, 0.5, 0.5, 0.5);
-
-    // graphics::draw_bitmap(video_buffer, &state.background_bitmap, 0.0, 0.0);
-
-    let screen_center_x = 0.5 * video_buffer.width as f32;
-    let screen_center_y = 0.5 * video_buffer.height as f32;
+    // Clear the screen to grey
+    render_group.push_clear(Color {
+        r: 0.5,
+        g: 0.5,
+        b: 0.5,
+        a: 1.0,
+    });
 
+    // The simulation never touches the assets, so the render group can hold
+    // on to the bitmaps until the end of the frame
+    let assets = &state.assets;
+    let controlled_heroes = &state.controlled_heroes;
 
     for index in 0..sim_region.entity_count {
-        let &mut GameState { ref hero_bitmaps, ref controlled_heroes,
-                             ref shadow, ref sword, ref tree, .. } = state;
+        let Assets { ref hero_bitmaps, ref shadow, ref sword, ref tree, .. } = *assets;
 
         let sim_entity: &mut SimEntity = sim_region.get_entity_ref(index);
         if sim_entity.can_update {
@@ -402,11 +453,9 @@ pub extern "C" fn update_and_render(context: &ThreadContext,
 
 
             let hero_bitmaps = &hero_bitmaps[sim_entity.face_direction as usize];
-            let mut piece_group = EntityPieceGroup {
-                meters_to_pixel: meters_to_pixel,
-                count: 0,
-                pieces: make_array!(None, 32),
-            };
+            let basis: &mut RenderBasis = transient_arena.push_struct();
+            *basis = RenderBasis::default();
+            let basis_ptr = basis as *const RenderBasis;
 
             let mut move_spec = MoveSpec::default();
 
@@ -449,31 +498,39 @@ pub extern "C" fn update_and_render(context: &ThreadContext,
                             }
                         }
                     }
-                    piece_group.push_bitmap(shadow,
-                                            V2::default(),
-                                            0.0,
-                                            hero_bitmaps.align,
-                                            0.0,
-                                            z_alpha);
-                    piece_group.push_bitmap(&hero_bitmaps.torso,
-                                            V2::default(),
-                                            0.0,
-                                            hero_bitmaps.align,
-                                            1.0,
-                                            1.0);
-                    piece_group.push_bitmap(&hero_bitmaps.cape,
-                                            V2::default(),
-                                            0.0,
-                                            hero_bitmaps.align,
-                                            1.0,
-                                            1.0);
-                    piece_group.push_bitmap(&hero_bitmaps.head,
-                                            V2::default(),
-                                            0.0,
-                                            hero_bitmaps.align,
-                                            1.0,
-                                            1.0);
-                    draw_hitpoints(sim_entity, &mut piece_group);
+                    render_group.push_bitmap(shadow,
+                                             basis_ptr,
+                                             RenderLayer::Shadow,
+                                             V2::default(),
+                                             0.0,
+                                             hero_bitmaps.align,
+                                             0.0,
+                                             z_alpha);
+                    render_group.push_bitmap(&hero_bitmaps.torso,
+                                             basis_ptr,
+                                             RenderLayer::Body,
+                                             V2::default(),
+                                             0.0,
+                                             hero_bitmaps.align,
+                                             1.0,
+                                             1.0);
+                    render_group.push_bitmap(&hero_bitmaps.cape,
+                                             basis_ptr,
+                                             RenderLayer::Body,
+                                             V2::default(),
+                                             0.0,
+                                             hero_bitmaps.align,
+                                             1.0,
+                                             1.0);
+                    render_group.push_bitmap(&hero_bitmaps.head,
+                                             basis_ptr,
+                                             RenderLayer::Body,
+                                             V2::default(),
+                                             0.0,
+                                             hero_bitmaps.align,
+                                             1.0,
+                                             1.0);
+                    draw_hitpoints(sim_entity, &mut render_group, basis_ptr);
 
                 }
 
@@ -482,33 +539,61 @@ pub extern "C" fn update_and_render(context: &ThreadContext,
                         //TODO: here we need to clear the collision rules!
                         sim_entity.make_non_spatial();
                     }
-                    piece_group.push_bitmap(shadow,
-                                            V2::default(),
-                                            0.0,
-                                            hero_bitmaps.align,
-                                            0.0,
-                                            z_alpha);
-                    piece_group.push_bitmap(sword, V2::default(), 0.0, V2 { x: 29, y: 13 }, 0.0, 1.0);
+                    render_group.push_bitmap(shadow,
+                                             basis_ptr,
+                                             RenderLayer::Shadow,
+                                             V2::default(),
+                                             0.0,
+                                             hero_bitmaps.align,
+                                             0.0,
+                                             z_alpha);
+                    // Points where it flies
+                    let velocity = sim_entity.velocity;
+                    let angle = if velocity.x != 0.0 || velocity.y != 0.0 {
+                        velocity.y.atan2(velocity.x)
+                    } else {
+                        0.0
+                    };
+                    render_group.push_rotated_bitmap(sword,
+                                                     basis_ptr,
+                                                     RenderLayer::Body,
+                                                     V2::default(),
+                                                     0.0,
+                                                     V2 { x: 29, y: 13 },
+                                                     0.0,
+                                                     angle,
+                                                     1.0);
                 }
 
                 EntityType::Monster => {
-                    piece_group.push_bitmap(shadow,
-                                            V2::default(),
-                                            0.0,
-                                            hero_bitmaps.align,
-                                            0.0,
-                                            z_alpha);
-                    piece_group.push_bitmap(&hero_bitmaps.torso,
-                                            V2::default(),
-                                            0.0,
-                                            hero_bitmaps.align,
-                                            1.0,
-                                            1.0);
-                    draw_hitpoints(sim_entity, &mut piece_group);
+                    render_group.push_bitmap(shadow,
+                                             basis_ptr,
+                                             RenderLayer::Shadow,
+                                             V2::default(),
+                                             0.0,
+                                             hero_bitmaps.align,
+                                             0.0,
+                                             z_alpha);
+                    render_group.push_bitmap(&hero_bitmaps.torso,
+                                             basis_ptr,
+                                             RenderLayer::Body,
+                                             V2::default(),
+                                             0.0,
+                                             hero_bitmaps.align,
+                                             1.0,
+                                             1.0);
+                    draw_hitpoints(sim_entity, &mut render_group, basis_ptr);
                 }
 
                 EntityType::Wall => {
-                    piece_group.push_bitmap(tree, V2::default(), 0.0, V2 { x: 40, y: 80 }, 1.0, 1.0);
+                    render_group.push_bitmap(tree,
+                                             basis_ptr,
+                                             RenderLayer::Body,
+                                             V2::default(),
+                                             0.0,
+                                             V2 { x: 40, y: 80 },
+                                             1.0,
+                                             1.0);
                 }
 
                 EntityType::Familiar => {
@@ -549,18 +634,22 @@ pub extern "C" fn update_and_render(context: &ThreadContext,
                         sim_entity.tbob -= 2.0 * PI;
                     }
                     let bob_sign = (sim_entity.tbob * 2.0).sin();
-                    piece_group.push_bitmap(&shadow,
-                                            V2::default(),
-                                            0.0,
-                                            hero_bitmaps.align,
-                                            0.0,
-                                            (0.5 * z_alpha) + 0.2 * bob_sign);
-                    piece_group.push_bitmap(&hero_bitmaps.head,
-                                            V2::default(),
-                                            0.25 * bob_sign,
-                                            hero_bitmaps.align,
-                                            1.0,
-                                            1.0);
+                    render_group.push_bitmap(&shadow,
+                                             basis_ptr,
+                                             RenderLayer::Shadow,
+                                             V2::default(),
+                                             0.0,
+                                             hero_bitmaps.align,
+                                             0.0,
+                                             (0.5 * z_alpha) + 0.2 * bob_sign);
+                    render_group.push_bitmap(&hero_bitmaps.head,
+                                             basis_ptr,
+                                             RenderLayer::Body,
+                                             V2::default(),
+                                             0.25 * bob_sign,
+                                             hero_bitmaps.align,
+                                             1.0,
+                                             1.0);
                 }
             }
 
@@ -572,43 +661,20 @@ pub extern "C" fn update_and_render(context: &ThreadContext,
                                    acc, 
                                    input.delta_t);
 
-            // move_entity can possibly make an entity none spatial so we need to
-            // check again if the entity has a position otherwise we don't need
-            // to draw stuff
+            // move_entity can possibly make an entity none spatial, its
+            // pieces stay without a position then and don't get drawn
             if let Some(position) = sim_entity.position {
-
-                let entity_groundpoint = V2 {
-                    x: screen_center_x + meters_to_pixel * position.x,
-                    y: screen_center_y - meters_to_pixel * position.y,
-                };
-
-                for index in 0..piece_group.count {
-                    let piece = piece_group.pieces[index].as_ref().unwrap();
-                    let piece_point = V2 {
-                        x: entity_groundpoint.x + piece.offset.x,
-                        y: entity_groundpoint.y + piece.offset.y + piece.offset_z -
-                            (meters_to_pixel * sim_entity.z) * piece.entity_zc,
-                    };
-
-                    if let Some(bitmap) = piece.bitmap {
-                        graphics::draw_bitmap_alpha(video_buffer,
-                                                    bitmap,
-                                                    piece_point,
-                                                    piece.alpha);
-                    } else {
-                        let half_dim = piece.dim * meters_to_pixel * 0.5;
-                        graphics::draw_rect(video_buffer,
-                                            piece_point - half_dim,
-                                            piece_point + half_dim,
-                                            piece.r,
-                                            piece.g,
-                                            piece.b);
-                    }
-                }
+                basis.p = Some(V3 {
+                    x: position.x,
+                    y: position.y,
+                    z: sim_entity.z,
+                });
             }
         }
     }
 
+    render_group::render_group_to_output(&mut render_group, video_buffer);
+
     sim_region.end_sim(state);
 }
 
@@ -697,7 +763,9 @@ pub fn should_collide(table: &[Option<PairCollisionRule>], a: &mut SimEntity, b:
     res
 }
 
-fn draw_hitpoints<'a>(sim_entity: &SimEntity, piece_group: &mut EntityPieceGroup<'a>) {
+fn draw_hitpoints<'a>(sim_entity: &SimEntity,
+                      render_group: &mut RenderGroup<'a>,
+                      basis: *const RenderBasis) {
     if sim_entity.max_hitpoints >= 1 {
         let health_dim = V2 { x: 0.2, y: 0.2 };
         let spacing_x = health_dim.x * 1.5;
@@ -727,7 +795,13 @@ fn draw_hitpoints<'a>(sim_entity: &SimEntity, piece_group: &mut EntityPieceGroup
                     a: 1.0,
                 }
             };
-            piece_group.push_rect(hit_p, 0.0, 0.0, health_dim, color);
+            render_group.push_rect(basis,
+                                   RenderLayer::Overlay,
+                                   hit_p,
+                                   0.0,
+                                   0.0,
+                                   health_dim,
+                                   color);
             hit_p = hit_p + d_hit_p;
         }
     }
@@ -870,6 +944,16 @@ pub struct HeroBitmaps<'a> {
     pub align: V2<i32>,
 }
 
+// Loaded once and only read afterwards, kept apart from the rest of the game
+// state so the bitmaps can be borrowed while the simulation changes it
+pub struct Assets<'a> {
+    pub background_bitmap: graphics::Bitmap<'a>,
+    pub shadow: graphics::Bitmap<'a>,
+    pub tree: graphics::Bitmap<'a>,
+    pub sword: graphics::Bitmap<'a>,
+    pub hero_bitmaps: [HeroBitmaps<'a>; 4],
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Ord, PartialOrd)]
 pub enum EntityType {
     Hero,
@@ -879,93 +963,11 @@ pub enum EntityType {
     Sword,
 }
 
-struct EntityPiece<'a> {
-    bitmap: Option<&'a graphics::Bitmap<'a>>,
-    offset: V2<f32>,
-    offset_z: f32,
-    alpha: f32,
-    entity_zc: f32,
-
-    r: f32,
-    g: f32,
-    b: f32,
-
-    dim: V2<f32>,
-}
-
-struct EntityPieceGroup<'a> {
-    meters_to_pixel: f32,
-    count: usize,
-    pieces: [Option<EntityPiece<'a>>; 32],
-}
-
-impl<'a> EntityPieceGroup<'a> {
-    fn push_piece(&mut self,
-                  bitmap: Option<&'a graphics::Bitmap<'a>>,
-                  offset: V2<f32>,
-                  offset_z: f32,
-                  dim: V2<f32>,
-                  color: Color,
-                  align: V2<f32>,
-                  entity_zc: f32) {
-        debug_assert!((self.count) < self.pieces.len());
-        let piece = &mut self.pieces[self.count];
-        self.count += 1;
-        *piece = Some(EntityPiece {
-            bitmap: bitmap,
-            offset: V2 {
-                x: offset.x,
-                y: -offset.y,
-            } * self.meters_to_pixel - align,
-            offset_z: offset_z * self.meters_to_pixel,
-            alpha: color.a,
-            entity_zc: entity_zc,
-
-            r: color.r,
-            g: color.g,
-            b: color.b,
-
-            dim: dim,
-        });
-    }
-
-    fn push_rect(&mut self,
-                 offset: V2<f32>,
-                 offset_z: f32,
-                 entity_zc: f32,
-                 dim: V2<f32>,
-                 color: Color) {
-        self.push_piece(None, offset, offset_z, dim, color, V2::default(), entity_zc);
-    }
-
-    fn push_bitmap(&mut self,
-                   bitmap: &'a graphics::Bitmap<'a>,
-                   offset: V2<f32>,
-                   offset_z: f32,
-                   align: V2<i32>,
-                   entity_zc: f32,
-                   alpha: f32) {
-
-        let color = Color {
-            r: 1.0,
-            g: 1.0,
-            b: 1.0,
-            a: alpha,
-        };
-        let align_float = V2 {
-            x: align.x as f32,
-            y: align.y as f32,
-        };
-        self.push_piece(Some(bitmap),
-                        offset,
-                        offset_z,
-                        V2::default(),
-                        color,
-                        align_float,
-                        entity_zc);
-    }
-}
+// Holds the copies of the bitmaps premultiplied in linear light
+const ASSET_MEMORY_SIZE: usize = 16 * 1024 * 1024;
 
+// Enough for every piece of every entity in the sim region
+const MAX_RENDER_ENTRIES: usize = 4096;
 
 pub const HITPOINTS_ARRAY_MAX: usize = 16;
 const HITPOINT_SUB_COUNT: u8 = 4;
@@ -1009,9 +1011,11 @@ pub struct ControlledHero {
 
 pub struct GameState<'a> {
     pub world_arena: MemoryArena,
+    pub asset_arena: MemoryArena,
     pub world: &'a mut World,
 
     pub meters_to_pixel: f32,
+    pub gamma_correct: bool,
 
     pub camera_follows_entity_index: Option<usize>,
     pub camera_position: WorldPosition,
@@ -1022,11 +1026,7 @@ pub struct GameState<'a> {
     pub lf_entity_count: usize,
     pub lf_entities: [LfEntity; 100000],
 
-    pub background_bitmap: graphics::Bitmap<'a>,
-    pub shadow: graphics::Bitmap<'a>,
-    pub tree: graphics::Bitmap<'a>,
-    pub sword: graphics::Bitmap<'a>,
-    pub hero_bitmaps: [HeroBitmaps<'a>; 4],
+    pub assets: Assets<'a>,
 
     // Must be a power of 2!
     pub pair_collision_rules: [Option<PairCollisionRule<'a>>; 256],
@@ -0,0 +1,332 @@
+// Render commands
+//
+// The simulation pushes what it wants to see into a RenderGroup and nothing
+// touches the VideoBuffer until render_group_to_output walks the entries.
+// Entries and bases come out of the transient arena and only live for the
+// frame they were pushed in.
+//
+// Before rasterizing, the entries are sorted for the top-down view:
+// shadows lie on the ground and go below everything standing on it, the
+// rest is drawn back to front by the world y of its basis, higher z on top
+// and by layer within the same spot. Pieces with the same key keep the
+// order they were pushed in.
+
+use std::cmp::Ordering;
+use std::ptr;
+
+use common::VideoBuffer;
+
+use super::graphics::{self, Bitmap, Color};
+use super::math::{V2, V3};
+use super::memory::MemoryArena;
+
+// Where a set of entries sits in the world, in meters relative to the
+// camera. An entity only knows its position once it moved, so the basis gets
+// filled in after its entries were pushed. Entries whose basis stays None
+// are not drawn.
+#[derive(Copy, Clone, Default)]
+pub struct RenderBasis {
+    pub p: Option<V3<f32>>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RenderLayer {
+    Shadow,
+    Body,
+    // Hit points and the like, above the body of the same entity
+    Overlay,
+}
+
+#[derive(Copy, Clone)]
+pub struct RenderPlacement {
+    basis: *const RenderBasis,
+    layer: RenderLayer,
+    // In pixels from the ground point of the basis, y pointing down
+    offset: V2<f32>,
+    offset_z: f32,
+    // How much the z of the basis lifts the entry
+    entity_zc: f32,
+}
+
+#[derive(Copy, Clone)]
+pub enum RenderEntry<'a> {
+    Clear {
+        color: Color,
+    },
+    Rectangle {
+        placement: RenderPlacement,
+        // In pixels
+        dim: V2<f32>,
+        color: Color,
+    },
+    Bitmap {
+        placement: RenderPlacement,
+        bitmap: &'a Bitmap<'a>,
+        alpha: f32,
+    },
+    // A bitmap mapped onto the axes (in pixels, y pointing down) from the
+    // placement on, filtered and tinted with color
+    TexturedQuad {
+        placement: RenderPlacement,
+        x_axis: V2<f32>,
+        y_axis: V2<f32>,
+        bitmap: &'a Bitmap<'a>,
+        color: Color,
+    },
+}
+
+#[derive(Copy, Clone)]
+struct SortKey {
+    // Clear, ground (shadows), standing
+    band: u8,
+    y: f32,
+    z: f32,
+    layer: RenderLayer,
+    index: usize,
+}
+
+fn compare_sort_keys(a: &SortKey, b: &SortKey) -> Ordering {
+    a.band
+     .cmp(&b.band)
+     .then_with(|| b.y.partial_cmp(&a.y).unwrap_or(Ordering::Equal))
+     .then_with(|| a.z.partial_cmp(&b.z).unwrap_or(Ordering::Equal))
+     .then_with(|| a.layer.cmp(&b.layer))
+     .then_with(|| a.index.cmp(&b.index))
+}
+
+pub struct RenderGroup<'a> {
+    pub meters_to_pixel: f32,
+    // Blend in linear space instead of directly on the sRGB values
+    pub gamma_correct: bool,
+    entries: &'a mut [RenderEntry<'a>],
+    sort_keys: &'a mut [SortKey],
+    count: usize,
+}
+
+impl<'a> RenderGroup<'a> {
+    pub fn new(arena: &mut MemoryArena,
+               max_entries: usize,
+               meters_to_pixel: f32,
+               gamma_correct: bool)
+               -> RenderGroup<'a> {
+        RenderGroup {
+            meters_to_pixel: meters_to_pixel,
+            gamma_correct: gamma_correct,
+            entries: arena.push_slice(max_entries),
+            sort_keys: arena.push_slice(max_entries),
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, entry: RenderEntry<'a>) {
+        debug_assert!(self.count < self.entries.len());
+        // The arena memory is uninitialized, nothing in there to drop
+        unsafe {
+            ptr::write(&mut self.entries[self.count], entry);
+        }
+        self.count += 1;
+    }
+
+    fn placement(&self,
+                 basis: *const RenderBasis,
+                 layer: RenderLayer,
+                 offset: V2<f32>,
+                 offset_z: f32,
+                 align: V2<f32>,
+                 entity_zc: f32)
+                 -> RenderPlacement {
+        RenderPlacement {
+            basis: basis,
+            layer: layer,
+            offset: V2 {
+                x: offset.x,
+                y: -offset.y,
+            } * self.meters_to_pixel - align,
+            offset_z: offset_z * self.meters_to_pixel,
+            entity_zc: entity_zc,
+        }
+    }
+
+    pub fn push_clear(&mut self, color: Color) {
+        self.push(RenderEntry::Clear { color: color });
+    }
+
+    // offset, offset_z and dim are in meters
+    pub fn push_rect(&mut self,
+                     basis: *const RenderBasis,
+                     layer: RenderLayer,
+                     offset: V2<f32>,
+                     offset_z: f32,
+                     entity_zc: f32,
+                     dim: V2<f32>,
+                     color: Color) {
+        let placement = self.placement(basis, layer, offset, offset_z, V2::default(), entity_zc);
+        let dim = dim * self.meters_to_pixel;
+        self.push(RenderEntry::Rectangle {
+            placement: placement,
+            dim: dim,
+            color: color,
+        });
+    }
+
+    // offset and offset_z are in meters, align is the pixel of the bitmap
+    // that ends up on the ground point
+    pub fn push_bitmap(&mut self,
+                       bitmap: &'a Bitmap<'a>,
+                       basis: *const RenderBasis,
+                       layer: RenderLayer,
+                       offset: V2<f32>,
+                       offset_z: f32,
+                       align: V2<i32>,
+                       entity_zc: f32,
+                       alpha: f32) {
+        let align = V2 {
+            x: align.x as f32,
+            y: align.y as f32,
+        };
+        let placement = self.placement(basis, layer, offset, offset_z, align, entity_zc);
+        self.push(RenderEntry::Bitmap {
+            placement: placement,
+            bitmap: bitmap,
+            alpha: alpha,
+        });
+    }
+
+    // Like push_bitmap, but the bitmap is turned by angle (radians, counter
+    // clockwise in the world) around align
+    pub fn push_rotated_bitmap(&mut self,
+                               bitmap: &'a Bitmap<'a>,
+                               basis: *const RenderBasis,
+                               layer: RenderLayer,
+                               offset: V2<f32>,
+                               offset_z: f32,
+                               align: V2<i32>,
+                               entity_zc: f32,
+                               angle: f32,
+                               alpha: f32) {
+        let (sin, cos) = angle.sin_cos();
+        let x_axis = V2 {
+            x: cos,
+            y: -sin,
+        } * bitmap.width() as f32;
+        let y_axis = V2 { x: sin, y: cos } * bitmap.height() as f32;
+        // The axes end up on the last texel center, so that's where the
+        // align gets scaled to as well
+        let align = x_axis * (align.x as f32 / (bitmap.width().max(2) - 1) as f32) +
+                    y_axis * (align.y as f32 / (bitmap.height().max(2) - 1) as f32);
+
+        let placement = self.placement(basis, layer, offset, offset_z, align, entity_zc);
+        self.push(RenderEntry::TexturedQuad {
+            placement: placement,
+            x_axis: x_axis,
+            y_axis: y_axis,
+            bitmap: bitmap,
+            color: Color {
+                r: 1.0,
+                g: 1.0,
+                b: 1.0,
+                a: alpha,
+            },
+        });
+    }
+
+    // Only valid once every basis got its position
+    fn sort(&mut self) {
+        for index in 0..self.count {
+            let key = match self.entries[index] {
+                RenderEntry::Clear { .. } => {
+                    SortKey {
+                        band: 0,
+                        y: 0.0,
+                        z: 0.0,
+                        layer: RenderLayer::Shadow,
+                        index: index,
+                    }
+                }
+                RenderEntry::Rectangle { ref placement, .. } |
+                RenderEntry::Bitmap { ref placement, .. } |
+                RenderEntry::TexturedQuad { ref placement, .. } => {
+                    let p = unsafe { (*placement.basis).p }.unwrap_or_default();
+                    SortKey {
+                        band: if placement.layer == RenderLayer::Shadow { 1 } else { 2 },
+                        y: p.y,
+                        z: p.z,
+                        layer: placement.layer,
+                        index: index,
+                    }
+                }
+            };
+            // The arena memory is uninitialized, nothing in there to drop
+            unsafe {
+                ptr::write(&mut self.sort_keys[index], key);
+            }
+        }
+
+        // The index makes every key unique, the unstable sort doesn't allocate
+        self.sort_keys[..self.count].sort_unstable_by(compare_sort_keys);
+    }
+}
+
+// Screen position of a placement, None if its basis never got a position
+fn placement_point(placement: &RenderPlacement,
+                   meters_to_pixel: f32,
+                   screen_center: V2<f32>)
+                   -> Option<V2<f32>> {
+    let basis = unsafe { &*placement.basis };
+    basis.p.map(|p| {
+        V2 {
+            x: screen_center.x + meters_to_pixel * p.x + placement.offset.x,
+            y: screen_center.y - meters_to_pixel * p.y + placement.offset.y + placement.offset_z -
+               meters_to_pixel * p.z * placement.entity_zc,
+        }
+    })
+}
+
+pub fn render_group_to_output(group: &mut RenderGroup, buffer: &mut VideoBuffer) {
+    group.sort();
+
+    let buffer_dim = V2 {
+        x: buffer.width as f32,
+        y: buffer.height as f32,
+    };
+    let screen_center = buffer_dim * 0.5;
+    let meters_to_pixel = group.meters_to_pixel;
+    let gamma_correct = group.gamma_correct;
+
+    for key in group.sort_keys[..group.count].iter() {
+        match group.entries[key.index] {
+            RenderEntry::Clear { color } => {
+                graphics::clear(buffer, color, gamma_correct);
+            }
+
+            RenderEntry::Rectangle { ref placement, dim, color } => {
+                if let Some(point) = placement_point(placement, meters_to_pixel, screen_center) {
+                    let half_dim = dim * 0.5;
+                    graphics::draw_rect(buffer,
+                                        point - half_dim,
+                                        point + half_dim,
+                                        color,
+                                        gamma_correct);
+                }
+            }
+
+            RenderEntry::Bitmap { ref placement, bitmap, alpha } => {
+                if let Some(point) = placement_point(placement, meters_to_pixel, screen_center) {
+                    graphics::draw_bitmap_alpha(buffer, bitmap, point, alpha, gamma_correct);
+                }
+            }
+
+            RenderEntry::TexturedQuad { ref placement, x_axis, y_axis, bitmap, color } => {
+                if let Some(point) = placement_point(placement, meters_to_pixel, screen_center) {
+                    graphics::draw_bitmap_slowly(buffer,
+                                                 point,
+                                                 x_axis,
+                                                 y_axis,
+                                                 bitmap,
+                                                 color,
+                                                 gamma_correct);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use libc::{mmap, MAP_PRIVATE, MAP_ANON, MAP_FAILED, PROT_READ, PROT_WRITE};
+use std::default::Default;
+use std::env;
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::ptr;
+use std::slice;
+use std::time::Instant;
+
+use linux_shared::{self, debug, file};
+use work_queue;
+use wav;
+use logging;
+use common::util;
+use common::{Input, SoundBuffer, VideoBuffer, GameMemory, ThreadContext};
+use common::timing::{FrameStats, FrameTiming};
+
+// The headless runner drives the game library without any window, audio
+// device or controller so the game code can be exercised on machines without
+// a display.
+const BUFFER_WIDTH: usize = 960;
+const BUFFER_HEIGHT: usize = 540;
+
+const SAMPLES_PER_SECOND: u32 = 48000;
+const GAME_REFRESH_RATE: u32 = 30;
+const DEFAULT_FRAME_COUNT: u32 = 300;
+const HIGH_PRIORITY_THREADS: u32 = 3;
+const LOW_PRIORITY_THREADS: u32 = 2;
+
+fn allocate_zeroed<'a, T>(count: usize) -> &'a mut [T] {
+    unsafe {
+        // Allocation implicitly freed at the end of the execution
+        let data = mmap(ptr::null_mut(),
+                        count * mem::size_of::<T>(),
+                        PROT_READ | PROT_WRITE,
+                        MAP_PRIVATE | MAP_ANON,
+                        -1,
+                        0);
+        if data.is_null() || data == MAP_FAILED {
+            panic!("Couldn't allocate the resources for the headless buffers!");
+        }
+
+        slice::from_raw_parts_mut(data as *mut T, count)
+    }
+}
+
+struct Options {
+    frame_count: u32,
+    capture_audio: Option<PathBuf>,
+}
+
+fn parse_options() -> Options {
+    let mut args = env::args().skip(1);
+    let mut options = Options {
+        frame_count: DEFAULT_FRAME_COUNT,
+        capture_audio: None,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--frames" => {
+                options.frame_count = match args.next().and_then(|value| value.parse().ok()) {
+                    Some(count) => count,
+                    None => {
+                        println!("--frames expects a positive number");
+                        process::exit(2);
+                    }
+                };
+            }
+            "--capture-audio" => {
+                match args.next() {
+                    Some(file) => options.capture_audio = Some(PathBuf::from(file)),
+                    None => {
+                        println!("--capture-audio expects a file name");
+                        process::exit(2);
+                    }
+                }
+            }
+            _ => {
+                println!("Unknown argument: {}", arg);
+                println!("Usage: rust_hero_headless [--frames N] [--capture-audio FILE]");
+                process::exit(2);
+            }
+        }
+    }
+
+    options
+}
+
+fn elapsed_seconds(start: Instant) -> f32 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000.0
+}
+
+// Cheap FNV-1a over the final frame so runs can be compared in automation.
+fn checksum(pixels: &[u32]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for pixel in pixels {
+        hash = (hash ^ *pixel as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn headlessmain() {
+    let options = parse_options();
+    let frame_count = options.frame_count;
+
+    let pixels: &mut [u32] = allocate_zeroed(BUFFER_WIDTH * BUFFER_HEIGHT);
+
+    let samples_per_frame = (SAMPLES_PER_SECOND / GAME_REFRESH_RATE) as usize;
+    // Two channels interleaved
+    let sound_samples: &mut [i16] = allocate_zeroed(samples_per_frame * 2);
+
+    let permanent_store_size = util::mega_bytes(64);
+    let transient_store_size = util::giga_bytes(1);
+    let memory = linux_shared::allocate_game_memory(permanent_store_size,
+                                                    transient_store_size,
+                                                    false)
+                     .unwrap_or_else(|_| process::exit(1));
+
+    let mut game_memory: GameMemory = GameMemory {
+        initialized: false,
+        restored: false,
+        permanent: unsafe { slice::from_raw_parts_mut(memory.permanent, permanent_store_size) },
+        transient: unsafe { slice::from_raw_parts_mut(memory.transient, transient_store_size) },
+        platform_read_entire_file: debug::platform_read_entire_file,
+        platform_write_entire_file: debug::platform_write_entire_file,
+        platform_free_file_memory: debug::platform_free_file_memory,
+        platform_open_file: file::platform_open_file,
+        platform_read_file: file::platform_read_file,
+        platform_close_file: file::platform_close_file,
+        platform_list_files: file::platform_list_files,
+        platform_log: logging::platform_log,
+        high_priority_queue: work_queue::create_work_queue(HIGH_PRIORITY_THREADS, 1, None),
+        low_priority_queue: work_queue::create_work_queue(LOW_PRIORITY_THREADS,
+                                                          HIGH_PRIORITY_THREADS + 1,
+                                                          None),
+        platform_add_work_entry: work_queue::platform_add_work_entry,
+        platform_complete_all_work: work_queue::platform_complete_all_work,
+        frame_stats: FrameStats::new(1.0 / GAME_REFRESH_RATE as f32),
+    };
+
+    let (game_so_string, temp_dir) = linux_shared::get_game_so_names();
+    let temp_so_name = linux_shared::temp_so_name(&temp_dir, 1);
+    let mut game = match linux_shared::load_game_functions(&game_so_string, &temp_so_name) {
+        Ok(game) => game,
+        Err(()) => {
+            println!("The game library could not be loaded! ({:?})", game_so_string);
+            process::exit(1);
+        }
+    };
+    if game.abi.game_state_size > permanent_store_size as u64 {
+        println!("The game state needs {} bytes, more than the permanent memory holds!",
+                 game.abi.game_state_size);
+        linux_shared::unload_game_functions(&mut game);
+        process::exit(1);
+    }
+
+    let mut audio_capture = match options.capture_audio {
+        Some(ref path) => {
+            match wav::create(path, SAMPLES_PER_SECOND) {
+                Ok(wav_file) => Some(wav_file),
+                Err(error) => {
+                    println!("The audio capture could not be created! ({:?}: {})", path, error);
+                    process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let thread_context = ThreadContext { index: 0 };
+
+    let mut new_input: &mut Input = &mut Default::default();
+    let mut old_input: &mut Input = &mut Default::default();
+
+    for frame in 0..frame_count {
+        // Frames are not paced, there is nothing to sleep or present
+        let mut timing: FrameTiming = Default::default();
+        let frame_start = Instant::now();
+
+        logging::set_frame(frame as u64);
+        new_input.delta_t = 1.0 / GAME_REFRESH_RATE as f32;
+        new_input.controllers[0] = old_input.controllers[0];
+        new_input.controllers[0].is_connected = true;
+        new_input.controllers[0].zero_half_transitions();
+
+        let mut video_buf = VideoBuffer {
+            memory: &mut pixels[..],
+            width: BUFFER_WIDTH,
+            height: BUFFER_HEIGHT,
+            pitch: BUFFER_WIDTH,
+        };
+
+        let update_start = Instant::now();
+        (game.update_and_render)(&thread_context, &mut game_memory, new_input, &mut video_buf);
+        timing.update = elapsed_seconds(update_start);
+
+        let mut sound_buffer = SoundBuffer {
+            samples: &mut sound_samples[..],
+            samples_per_second: SAMPLES_PER_SECOND,
+        };
+
+        let sound_start = Instant::now();
+        (game.get_sound_samples)(&thread_context, &mut game_memory, &mut sound_buffer);
+        timing.sound = elapsed_seconds(sound_start);
+
+        // Work left running into the next frame would make the checksum
+        // depend on thread timing
+        linux_shared::complete_all_game_work(&thread_context, &game_memory);
+
+        if let Some(ref mut wav_file) = audio_capture {
+            if let Err(error) = wav_file.write_samples(sound_buffer.samples) {
+                println!("Writing the audio capture failed! ({})", error);
+                process::exit(1);
+            }
+        }
+
+        timing.total = elapsed_seconds(frame_start);
+        timing.missed = timing.total >= game_memory.frame_stats.target_seconds;
+        game_memory.frame_stats.push(timing);
+
+        mem::swap(new_input, old_input);
+    }
+
+    println!("Ran {} frames, final frame checksum {:016x}",
+             frame_count,
+             checksum(pixels));
+    game_memory.frame_stats.print_report();
+
+    if let Some(wav_file) = audio_capture {
+        if let Err(error) = wav_file.finish() {
+            println!("The audio capture could not be finished! ({})", error);
+            process::exit(1);
+        }
+    }
+
+    linux_shared::unload_game_functions(&mut game);
+}
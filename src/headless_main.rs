@@ -0,0 +1,32 @@
+#![feature(asm, plugin)]
+//#![plugin(clippy)]
+
+#![allow(non_snake_case)]
+#![allow(non_upper_case_globals)]
+extern crate libc;
+
+mod common;
+
+mod work_queue;
+
+#[cfg(target_os="linux")]
+mod ffi {
+    pub mod linux;
+}
+
+#[cfg(target_os="linux")]
+mod linux_shared;
+
+#[cfg(target_os="linux")]
+mod wav;
+
+#[cfg(target_os="linux")]
+mod logging;
+
+#[cfg(target_os="linux")]
+mod headless;
+
+#[cfg(target_os="linux")]
+fn main() {
+    headless::headlessmain();
+}
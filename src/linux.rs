@@ -1,22 +1,40 @@
-use libc::{mode_t, size_t, S_IRUSR, S_IWUSR, S_IXUSR};
-use libc::{open, close, mmap, munmap, MAP_PRIVATE, MAP_FAILED, MAP_ANON};
-use libc::{O_RDONLY, O_WRONLY, O_CREAT, PROT_READ, PROT_WRITE, fstat, stat};
+use libc::{size_t, S_IRUSR, S_IWUSR};
+use libc::{open, close, mmap, munmap, MAP_PRIVATE, MAP_SHARED, MAP_FAILED, MAP_ANON};
+use libc::{O_RDWR, O_CREAT, O_TRUNC, PROT_READ, PROT_WRITE};
+use libc::{ftruncate, off_t, access, stat, F_OK, sysconf, _SC_NPROCESSORS_ONLN};
+use std::cmp;
 use std::default::Default;
+use std::env;
+use std::process;
 use std::ptr;
 use std::mem;
 use std::path::PathBuf;
 use std::slice;
-use std::fs::read_link;
+use std::sync::Arc;
+use std::fs::File;
+use std::time::{Duration, Instant};
 use std::ffi::CString;
 
 use ffi::sdl::*;
 use ffi::linux;
-use common::util;
-use common::{GetSoundSamplesT, UpdateAndRenderT, Input, SoundBuffer, Button};
+use linux_shared::{debug, file, Game, GameMemoryBlock, S_IRGRP, S_IROTH};
+use linux_shared::{allocate_game_memory, complete_all_game_work, game_so_names_for};
+use linux_shared::{get_exe_path, get_game_so_names, temp_so_name};
+use linux_shared::{load_game_functions, unload_game_functions};
+use linux_shared::{get_sound_samples_stub, update_and_render_stub};
+use bindings::{Bindings, LogicalButton};
+use crash;
+use framedump::{self, FrameDumper};
+use logging;
+use options::{self, AudioBackend, Options, ParseError, Scaling};
+use wav::{self, WavFile};
+use audio::{self, AudioRing, AudioLatency, RealtimeSink};
+use work_queue;
+use common::recording::{RecordingReader, RecordingWriter};
+use common::{Input, SoundBuffer, Button};
 use common::{ControllerInput, VideoBuffer, GameMemory, ThreadContext};
-
-const S_IRGRP: mode_t = 32;
-const S_IROTH: mode_t = 4;
+use common::{GameAbi, GAME_ABI_VERSION};
+use common::timing::{FrameStats, FrameTiming};
 
 #[derive(Eq, PartialEq)]
 enum TimeComp {
@@ -25,146 +43,19 @@ enum TimeComp {
     Same,
 }
 
-#[allow(unused_imports)]
-#[allow(dead_code)]
-#[cfg(feature = "internal")]
-mod debug {
-    use libc::{c_void, open, close, mmap, munmap, O_RDONLY, O_CREAT};
-    use libc::{MAP_ANON, MAP_PRIVATE, MAP_FAILED, stat, write, read, fstat};
-    use libc::{PROT_READ, PROT_WRITE, S_IRUSR, S_IWUSR};
-    use libc::{O_WRONLY, mode_t, size_t};
-    use std::ptr;
-    use std::default::Default;
-    use std::ffi::CString;
-    use std::mem;
-
-    use common::{ThreadContext, ReadFileResult};
-    use common::util;
-
-
-    pub fn platform_read_entire_file(context: &ThreadContext,
-                                     filename: &str)
-                                     -> Result<ReadFileResult, ()> {
-
-        let mut result: Result<ReadFileResult, ()> = Err(());
-        let name = CString::new(filename).unwrap();
-        let handle = unsafe { open(name.as_ptr(), O_RDONLY, 0) };
-
-        if handle != -1 {
-            let mut file_stat: stat = unsafe { mem::uninitialized() };
-            if unsafe { fstat(handle, &mut file_stat) != -1 } {
-                let size = util::safe_truncate_u64(file_stat.st_size as u64);
-                let memory: *mut c_void = unsafe {
-                    mmap(ptr::null_mut(),
-                         size as size_t,
-                         PROT_WRITE,
-                         MAP_PRIVATE | MAP_ANON,
-                         -1,
-                         0)
-                };
-
-                if !memory.is_null() {
-                    let mut bytes_to_read = size;
-                    let mut next_write_byte: *mut u8 = memory as *mut u8;
-
-                    while bytes_to_read > 0 {
-                        let bytes_read = unsafe {
-                            read(handle,
-                                 next_write_byte as *mut c_void,
-                                 bytes_to_read as usize)
-                        };
-                        if bytes_read == -1 {
-                            break;
-                        }
-                        bytes_to_read -= bytes_read as u32;
-                        next_write_byte = unsafe { next_write_byte.offset(bytes_read as isize) };
-                    }
-
-                    if bytes_to_read == 0 {
-                        result = Ok(ReadFileResult {
-                            size: size,
-                            contents: memory as *mut u8,
-                        });
-                    } else {
-                        println!("Reading the file contents failed! ({})", filename);
-                        platform_free_file_memory(context, memory as *mut u8, size);
-                    }
-                } else {
-                    println!("Not enough memory could be optained!");
-                }
-            } else {
-                println!("Fstat for the File was not successfull! ({})", filename);
-            }
-            unsafe {
-                close(handle);
-            }
-        } else {
-            println!("The File could not be opened! ({})", filename);
-        }
-
-        result
-    }
-
-    pub fn platform_free_file_memory(_context: &ThreadContext, memory: *mut u8, size: u32) {
-        if !memory.is_null() {
-            unsafe {
-                munmap(memory as *mut c_void, size as usize);
-            }
-        }
-    }
-
-    pub fn platform_write_entire_file(_context: &ThreadContext,
-                                      filename: &str,
-                                      size: u32,
-                                      memory: *mut u8)
-                                      -> bool {
-        let mut result = false;
-        let name = CString::new(filename).unwrap();
-        let handle = unsafe {
-            open(name.as_ptr(),
-                 O_WRONLY | O_CREAT,
-                 S_IRUSR | S_IWUSR | super::S_IRGRP | super::S_IROTH)
-        };
-
-        if handle != -1 {
-            let mut bytes_to_write = size;
-            let mut byte_to_write: *mut u8 = memory;
-
-            while bytes_to_write > 0 {
-                let bytes_written = unsafe {
-                    write(handle,
-                          byte_to_write as *const c_void,
-                          bytes_to_write as usize)
-                };
-                if bytes_written == -1 {
-                    break;
-                }
-                bytes_to_write -= bytes_written as u32;
-                byte_to_write = unsafe { byte_to_write.offset(bytes_written as isize) };
-            }
-
-            if bytes_to_write == 0 {
-                result = true;
-            }
-
-            unsafe {
-                close(handle);
-            }
-        }
-        result
-    }
-
-}
-
 const MAX_CONTROLLERS: c_int = 4;
+// Same value XInput recommends for the left thumb stick
+const CONTROLLER_DEAD_ZONE: i16 = 7849;
 
 const BYTES_PER_PIXEL: u32 = 4;
-const WINDOW_WIDTH: i32 = 960;
-const WINDOW_HEIGHT: i32 = 540;
 
 const SAMPLES_PER_SECOND: i32 = 48000;
 const BYTES_PER_SAMPLE: i32 = 4;
 
+// Long running work like asset loading must not starve the high priority
+// queue, so it gets its own threads
+const LOW_PRIORITY_THREADS: u32 = 2;
+
 struct BackBuffer {
     pixels: *mut c_void,
     size: size_t,
@@ -172,40 +63,207 @@ struct BackBuffer {
     height: i32,
     texture: *mut SDL_Texture,
     texture_width: i32,
+    scaling: Scaling,
 }
 
-struct SdlAudioRingBuffer {
-    size: c_int,
-    write_cursor: c_int,
-    play_cursor: c_int,
-    data: *mut u8,
+#[derive(PartialEq, Eq)]
+enum ReplayState {
+    Recording,
+    Replaying,
+    Nothing,
 }
 
-struct SdlSoundOutput {
-    running_sample_idx: i32,
-    secondary_buffer_size: i32,
-    latency_sample_count: i32,
+struct Replay {
+    input_path: PathBuf,
+    recorder: Option<RecordingWriter<File>>,
+    player: Option<RecordingReader<File>>,
+    // Checksums of the frames rendered with the recorded input, written
+    // while recording and compared against while replaying
+    checksum_path: PathBuf,
+    checksum_writer: Option<File>,
+    checksums: Vec<u64>,
+    differing_frames: u32,
+    // The stores aren't contiguous, the snapshot holds the permanent store
+    // followed by the transient store
+    permanent: *mut u8,
+    transient: *mut u8,
+    memory: *mut c_void,
+    permanent_size: usize,
+    transient_size: usize,
+    delta_t: f32,
+    state: ReplayState,
 }
 
-struct Game {
-    handle: *mut c_void,
-    get_sound_samples: GetSoundSamplesT,
-    update_and_render: UpdateAndRenderT,
-    write_time: linux::timespec,
-}
+impl Replay {
+    fn is_recording(&self) -> bool {
+        self.state == ReplayState::Recording
+    }
 
+    fn is_replaying(&self) -> bool {
+        self.state == ReplayState::Replaying
+    }
 
-// Stub functons if none of the game Code could be loaded!
-extern "C" fn get_sound_samples_stub(_: &ThreadContext, _: &mut GameMemory, _: &mut SoundBuffer) {
-    unimplemented!();
-}
-extern "C" fn update_and_render_stub(_: &ThreadContext,
-                                     _: &mut GameMemory,
-                                     _: &Input,
-                                     _: &mut VideoBuffer) {
-    unimplemented!();
+    // Index of the frame that was just read from the recording
+    fn playback_frame(&self) -> Option<u32> {
+        match self.player {
+            Some(ref player) if self.is_replaying() => Some(player.frames_read()),
+            _ => None,
+        }
+    }
+
+    fn restore_snapshot(&mut self) {
+        unsafe {
+            let snapshot = self.memory as *const u8;
+            ptr::copy_nonoverlapping(snapshot, self.permanent, self.permanent_size);
+            ptr::copy_nonoverlapping(snapshot.offset(self.permanent_size as isize),
+                                     self.transient,
+                                     self.transient_size);
+        }
+    }
+
+    fn take_snapshot(&mut self) {
+        unsafe {
+            let snapshot = self.memory as *mut u8;
+            ptr::copy_nonoverlapping(self.permanent, snapshot, self.permanent_size);
+            ptr::copy_nonoverlapping(self.transient,
+                                     snapshot.offset(self.permanent_size as isize),
+                                     self.transient_size);
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        self.checksum_writer = None;
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(error) = recorder.finish() {
+                println!("The input recording could not be finished! ({})", error);
+            }
+        }
+        self.state = ReplayState::Nothing;
+    }
+
+    // Returns false if there is nothing to replay, the memory stays
+    // untouched then
+    fn start_replay(&mut self) -> bool {
+        let player = File::open(&self.input_path).and_then(RecordingReader::new);
+        match player {
+            Ok(player) => {
+                let header = *player.header();
+                if header.permanent_size != self.permanent_size as u64 ||
+                   header.transient_size != self.transient_size as u64 {
+                    println!("The input recording was made with different memory sizes!");
+                    return false;
+                }
+                // Nothing to loop over, the snapshot stays untouched
+                if header.frame_count == 0 {
+                    println!("The input recording is empty!");
+                    return false;
+                }
+                self.checksums = framedump::read_checksums(&self.checksum_path)
+                                     .unwrap_or_else(|_| Vec::new());
+                self.differing_frames = 0;
+                self.player = Some(player);
+                self.restore_snapshot();
+                self.state = ReplayState::Replaying;
+                true
+            }
+            Err(error) => {
+                println!("The input recording could not be read! ({})", error);
+                false
+            }
+        }
+    }
+
+    // Gets the checksum of every rendered frame
+    fn check_frame(&mut self, checksum: u64) {
+        if let Some(mut writer) = self.checksum_writer.take() {
+            match framedump::write_checksum(&mut writer, checksum) {
+                Ok(()) => self.checksum_writer = Some(writer),
+                Err(error) => println!("Writing the frame checksums failed! ({})", error),
+            }
+        } else if let Some(frame) = self.playback_frame() {
+            match self.checksums.get(frame as usize - 1) {
+                Some(&recorded) if recorded != checksum => {
+                    if self.differing_frames == 0 {
+                        println!("Frame {} of the playback differs from the recording!", frame);
+                    }
+                    self.differing_frames += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // After one full pass over the recording
+    fn print_playback_check(&self) {
+        let frame_count = self.player.as_ref().map_or(0, |player| player.header().frame_count);
+        if self.checksums.len() < frame_count as usize {
+            println!("The recording has no frame checksums, the playback can't be checked!");
+        } else if self.differing_frames == 0 {
+            println!("The playback reproduced all {} recorded frames", frame_count);
+        } else {
+            println!("{} of {} played back frames differ from the recording!",
+                     self.differing_frames,
+                     frame_count);
+        }
+    }
+
+    fn stop_replay(&mut self) {
+        self.player = None;
+        self.state = ReplayState::Nothing;
+    }
+
+    fn start_recording(&mut self) {
+        let recorder = File::create(&self.input_path).and_then(|file| {
+            RecordingWriter::new(file,
+                                 self.permanent_size as u64,
+                                 self.transient_size as u64,
+                                 self.delta_t)
+        });
+        match recorder {
+            Ok(recorder) => {
+                self.recorder = Some(recorder);
+                self.checksum_writer = match File::create(&self.checksum_path) {
+                    Ok(file) => Some(file),
+                    Err(error) => {
+                        println!("The frame checksums can't be written! ({})", error);
+                        None
+                    }
+                };
+                self.take_snapshot();
+                self.state = ReplayState::Recording;
+            }
+            Err(error) => println!("The input recording could not be created! ({})", error),
+        }
+    }
+
+    fn cycle(&mut self) {
+        if self.is_recording() {
+            self.stop_recording();
+            self.start_replay();
+        } else if self.is_replaying() {
+            self.stop_replay();
+        } else {
+            self.start_recording();
+        }
+    }
 }
 
+impl Game {
+    // Used while no game library could be loaded yet, so the platform keeps
+    // running until a build shows up.
+    pub fn stub() -> Game {
+        Game {
+            handle: ptr::null_mut(),
+            get_sound_samples: get_sound_samples_stub,
+            update_and_render: update_and_render_stub,
+            abi: GameAbi {
+                version: GAME_ABI_VERSION,
+                game_state_size: 0,
+            },
+            temp_so_name: None,
+        }
+    }
+}
 
 fn get_last_write_time(file_path: &CString) -> linux::timespec {
     let mut file_stat = unsafe { mem::uninitialized() };
@@ -222,119 +280,142 @@ fn get_last_write_time(file_path: &CString) -> linux::timespec {
     }
 }
 
-fn load_game_functions(game_so_name: &CString, temp_so_name: &CString) -> Game {
+fn get_file_size(file_path: &CString) -> Option<off_t> {
+    let mut file_stat: stat = unsafe { mem::uninitialized() };
+    if unsafe { stat(file_path.as_ptr(), &mut file_stat) != -1 } {
+        Some(file_stat.st_size)
+    } else {
+        None
+    }
+}
 
-    let in_fd = unsafe { open(game_so_name.as_ptr(), O_RDONLY, 0) };
-    let out_fd = unsafe {
-        open(temp_so_name.as_ptr(),
-             O_WRONLY | O_CREAT,
-             S_IRUSR | S_IWUSR | S_IXUSR | S_IRGRP | S_IROTH)
-    };
+// How long the library has to stay untouched before we trust the build to be
+// done writing it
+const RELOAD_SETTLE_MS: u64 = 250;
+
+// Decides when a rebuilt game library is safe to load. The build can signal
+// that it is still busy with a lock file next to the library, otherwise the
+// library has to keep the same write time and size for RELOAD_SETTLE_MS.
+pub struct GameReloader {
+    game_so_name: CString,
+    lock_name: CString,
+    temp_dir: PathBuf,
+    load_count: u32,
+    seen_write_time: linux::timespec,
+    pending: Option<(linux::timespec, Option<off_t>, Instant)>,
+}
 
-    let mut result = Game {
-        handle: ptr::null_mut(),
-        get_sound_samples: get_sound_samples_stub,
-        update_and_render: update_and_render_stub,
-        write_time: linux::timespec {
-            tv_sec: 0,
-            tv_nsec: 0,
-        },
-    };
+impl GameReloader {
+    pub fn new(game_so_name: CString, temp_dir: PathBuf) -> GameReloader {
+        let mut lock_name = game_so_name.as_bytes().to_vec();
+        lock_name.extend_from_slice(b".lock");
+
+        GameReloader {
+            game_so_name: game_so_name,
+            lock_name: CString::new(lock_name).unwrap(),
+            temp_dir: temp_dir,
+            load_count: 0,
+            seen_write_time: linux::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            pending: None,
+        }
+    }
 
-    let mut file_stat: stat = unsafe { mem::uninitialized() };
-    if unsafe { fstat(in_fd, &mut file_stat) != -1 } {
-        let size = file_stat.st_size as usize;
-        unsafe {
-            linux::sendfile(out_fd, in_fd, ptr::null_mut(), size);
-            result.write_time = get_last_write_time(temp_so_name);
-            result.handle = linux::dlopen(temp_so_name.as_ptr(), linux::RTLD_LAZY);
-
-            if !result.handle.is_null() {
-                println!("Found corresponding file!");
-                let get_sound_samples_name = CString::new("get_sound_samples").unwrap();
-                let update_and_render_name = CString::new("update_and_render").unwrap();
-
-                let get_sound_samples = linux::dlsym(result.handle,
-                                                     get_sound_samples_name.as_ptr());
-                let update_and_render = linux::dlsym(result.handle,
-                                                     update_and_render_name.as_ptr());
-                if !get_sound_samples.is_null() && !update_and_render.is_null() {
-                    println!("Found both functions!");
-                    result.get_sound_samples = mem::transmute(get_sound_samples);
-                    result.update_and_render = mem::transmute(update_and_render);
-                }
-            } else {
-                println!("{:?}", linux::dl_error());
+    pub fn library_changed(&mut self) -> bool {
+        let write_time = get_last_write_time(&self.game_so_name);
+        if compare_file_time(&self.seen_write_time, &write_time) == TimeComp::Same {
+            self.pending = None;
+            return false;
+        }
+
+        let lock_exists = unsafe { access(self.lock_name.as_ptr(), F_OK) } == 0;
+        if lock_exists {
+            self.pending = None;
+            return false;
+        }
+
+        let size = get_file_size(&self.game_so_name);
+        let unchanged_since = match self.pending {
+            Some((ref pending_time, pending_size, since))
+                if compare_file_time(pending_time, &write_time) == TimeComp::Same &&
+                   pending_size == size => Some(since),
+            _ => None,
+        };
+
+        match unchanged_since {
+            Some(since) => since.elapsed() >= Duration::from_millis(RELOAD_SETTLE_MS),
+            None => {
+                self.pending = Some((write_time, size, Instant::now()));
+                false
             }
         }
     }
 
-    unsafe {
-        close(in_fd);
-        close(out_fd);
+    // Returns the freshly loaded library or None if the old one has to be
+    // kept. Either way this build is not tried again until it changes.
+    pub fn try_load(&mut self) -> Option<Game> {
+        self.seen_write_time = get_last_write_time(&self.game_so_name);
+        self.pending = None;
+
+        self.load_count += 1;
+        let temp_so_name = temp_so_name(&self.temp_dir, self.load_count);
+        match load_game_functions(&self.game_so_name, &temp_so_name) {
+            Ok(game) => Some(game),
+            Err(()) => {
+                println!("Keeping the previously loaded game library!");
+                None
+            }
+        }
     }
-
-    result
 }
 
-fn unload_game_functions(game: &mut Game) {
-    if !game.handle.is_null() {
-        unsafe {
-            linux::dlclose(game.handle);
-        }
-        game.handle = ptr::null_mut();
+fn compare_file_time(time1: &linux::timespec, time2: &linux::timespec) -> TimeComp {
+    if (time1.tv_sec == time2.tv_sec) && (time1.tv_nsec == time2.tv_nsec) {
+        TimeComp::Same
+    } else if (time1.tv_sec > time2.tv_sec) ||
+       ((time1.tv_sec == time2.tv_sec) && (time1.tv_nsec > time2.tv_nsec)) {
+        TimeComp::Earlier
+    } else {
+        TimeComp::Later
     }
-    game.get_sound_samples = get_sound_samples_stub;
-    game.update_and_render = update_and_render_stub;
 }
 
-extern "C" fn audio_callback(user_data: *mut c_void, audio_data: *mut u8, length: c_int) {
-    let ring_buffer: &mut SdlAudioRingBuffer = unsafe{ &mut *(user_data as *mut SdlAudioRingBuffer) };
-
-    let mut region_size = length;
-    let region2_size = if ring_buffer.play_cursor + length > ring_buffer.size {
-        region_size = ring_buffer.size - ring_buffer.play_cursor;
-        length - region_size
-    } else {
-        0
-    };
+// One worker per core next to the main thread, but at least one
+fn high_priority_thread_count() -> u32 {
+    let cores = unsafe { sysconf(_SC_NPROCESSORS_ONLN) };
+    if cores > 1 { (cores - 1) as u32 } else { 1 }
+}
 
-    copy_contents(audio_data,
-                  unsafe {
-                      ring_buffer.data
-                                 .offset(ring_buffer.play_cursor as isize)
-                  },
-                  region_size);
-    copy_contents(unsafe { audio_data.offset(region_size as isize) },
-                  ring_buffer.data,
-                  region2_size);
-
-    fn copy_contents(out: *mut u8, src: *mut u8, size: c_int) {
-        let mut out = out;
-        let mut src = src;
-        for _ in 0..size {
-            unsafe {
-                *out = *src;
-                out = out.offset(1);
-                src = src.offset(1);
-            }
-        }
+fn reset_game_memory(game_memory: &mut GameMemory) {
+    unsafe {
+        ptr::write_bytes(game_memory.permanent.as_mut_ptr(), 0, game_memory.permanent.len());
+        ptr::write_bytes(game_memory.transient.as_mut_ptr(), 0, game_memory.transient.len());
     }
+    game_memory.initialized = false;
+    game_memory.restored = false;
+}
 
-    ring_buffer.play_cursor = (ring_buffer.play_cursor + length) % ring_buffer.size;
-    ring_buffer.write_cursor = (ring_buffer.write_cursor + length) % ring_buffer.size;
+extern "C" fn audio_callback(user_data: *mut c_void, audio_data: *mut u8, length: c_int) {
+    let ring: &AudioRing = unsafe { &*(user_data as *const AudioRing) };
+    let out = unsafe {
+        slice::from_raw_parts_mut(audio_data as *mut i16,
+                                  length as usize / mem::size_of::<i16>())
+    };
+    ring.read(out, unsafe { SDL_GetPerformanceCounter() });
 }
 
 
-#[allow(dead_code)]
 fn process_game_controller_button(old_state: &Button, new_state: &mut Button, value: bool) {
     new_state.ended_down = value;
-    if new_state.ended_down == old_state.ended_down {
-        new_state.half_transitions += 1;
-    }
+    new_state.half_transitions = if old_state.ended_down == new_state.ended_down {
+        0
+    } else {
+        1
+    };
 }
 
-#[allow(dead_code)]
 fn process_game_controller_axis(value: i16, dead_zone: i16) -> f32 {
     let mut result = 0.0f32;
 
@@ -347,96 +428,186 @@ fn process_game_controller_axis(value: i16, dead_zone: i16) -> f32 {
     result
 }
 
-fn fill_sound_buffer(output: &mut SdlSoundOutput,
-                     byte_to_lock: i32,
-                     bytes_to_write: i32,
-                     buffer: &SoundBuffer,
-                     ring_buffer: &mut SdlAudioRingBuffer) {
-
-    let region: *mut u8 = unsafe {
-        ring_buffer.data
-                   .offset(byte_to_lock as isize)
-    };
-
-    let region_size = if bytes_to_write + byte_to_lock > output.secondary_buffer_size {
-        output.secondary_buffer_size - byte_to_lock
+fn process_game_controller(controller: *mut SDL_GameController,
+                           bindings: &Bindings,
+                           old_controller: &ControllerInput,
+                           new_controller: &mut ControllerInput) {
+    use ffi::sdl::SDL_GameControllerAxis::*;
+
+    new_controller.is_connected = true;
+
+    let stick_x = unsafe { SDL_GameControllerGetAxis(controller, SDL_CONTROLLER_AXIS_LEFTX) };
+    let stick_y = unsafe { SDL_GameControllerGetAxis(controller, SDL_CONTROLLER_AXIS_LEFTY) };
+
+    let mut x_value = process_game_controller_axis(stick_x, CONTROLLER_DEAD_ZONE);
+    // SDL reports the y axis growing downwards but the game expects up to
+    // be positive
+    let mut y_value = -process_game_controller_axis(stick_y, CONTROLLER_DEAD_ZONE);
+
+    let digital_up = bindings.is_pad_button_down(LogicalButton::MoveUp, controller);
+    let digital_down = bindings.is_pad_button_down(LogicalButton::MoveDown, controller);
+    let digital_left = bindings.is_pad_button_down(LogicalButton::MoveLeft, controller);
+    let digital_right = bindings.is_pad_button_down(LogicalButton::MoveRight, controller);
+
+    // If no digital movement button (the DPAD by default) was used default
+    // to analog input with the controllers
+    if digital_up || digital_down || digital_left || digital_right {
+        new_controller.average_x = None;
+        new_controller.average_y = None;
+        if digital_up {
+            y_value = 1.0;
+        } else if digital_down {
+            y_value = -1.0;
+        }
+        if digital_left {
+            x_value = -1.0;
+        } else if digital_right {
+            x_value = 1.0;
+        }
     } else {
-        bytes_to_write
-    };
-
-    let region2: *mut u8 = ring_buffer.data;
-    let region2_size = bytes_to_write - region_size;
-
-    let mut samples = buffer.samples.as_ptr();
+        new_controller.average_x = Some(x_value);
+        new_controller.average_y = Some(y_value);
+    }
 
-    let region_sample_count = region_size / BYTES_PER_SAMPLE;
-    let mut sample_out = region as *mut i16;
-    for _ in 0..region_sample_count {
-        unsafe {
-            *sample_out = *samples;
-            sample_out = sample_out.offset(1);
-            samples = samples.offset(1);
+    let threshhold = 0.5;
+    process_game_controller_button(&old_controller.move_up,
+                                   &mut new_controller.move_up,
+                                   y_value > threshhold);
+    process_game_controller_button(&old_controller.move_down,
+                                   &mut new_controller.move_down,
+                                   y_value < -threshhold);
+    process_game_controller_button(&old_controller.move_left,
+                                   &mut new_controller.move_left,
+                                   x_value < -threshhold);
+    process_game_controller_button(&old_controller.move_right,
+                                   &mut new_controller.move_right,
+                                   x_value > threshhold);
+
+    for &logical in &[LogicalButton::ActionUp,
+                      LogicalButton::ActionDown,
+                      LogicalButton::ActionLeft,
+                      LogicalButton::ActionRight,
+                      LogicalButton::LeftShoulder,
+                      LogicalButton::RightShoulder,
+                      LogicalButton::Start,
+                      LogicalButton::Back] {
+        process_game_controller_button(logical.button(old_controller),
+                                       logical.button_mut(new_controller),
+                                       bindings.is_pad_button_down(logical, controller));
+    }
+}
 
-            *sample_out = *samples;
-            sample_out = sample_out.offset(1);
-            samples = samples.offset(1);
+// Hands the samples the game produced to the audio thread
+fn fill_sound_buffer(buffer: &SoundBuffer, ring: &AudioRing, capture: &mut Option<WavFile>) {
+    let frames = ring.write(buffer.samples);
 
-            output.running_sample_idx += 1;
+    // Everything that goes into the ring buffer goes into the capture too
+    let mut capture_failed = false;
+    if let Some(ref mut wav_file) = *capture {
+        if let Err(error) = wav_file.write_samples(&buffer.samples[..frames * audio::CHANNELS]) {
+            println!("Writing the audio capture failed, stopping it! ({})", error);
+            capture_failed = true;
         }
     }
+    if capture_failed {
+        *capture = None;
+    }
+}
 
-    let region2_sample_count = region2_size / BYTES_PER_SAMPLE;
-    let mut sample_out = region2 as *mut i16;
-    for _ in 0..region2_sample_count {
-        unsafe {
-            *sample_out = *samples;
-            sample_out = sample_out.offset(1);
-            samples = samples.offset(1);
+// Chunk size the SDL device asks for and the sinks hand out
+const AUDIO_DEVICE_FRAMES: u16 = 512;
 
-            *sample_out = *samples;
-            sample_out = sample_out.offset(1);
-            samples = samples.offset(1);
+enum AudioOutput {
+    Sdl,
+    Sink(RealtimeSink),
+}
 
-            output.running_sample_idx += 1;
-        }
-    }
+fn performance_counter() -> u64 {
+    unsafe { SDL_GetPerformanceCounter() }
 }
 
-fn init_audio(samples_per_second: i32, buffer_size: i32, ring_buffer: &mut SdlAudioRingBuffer) {
+fn init_sdl_audio(samples_per_second: i32, ring: &AudioRing) -> Result<(), ()> {
+    if unsafe { SDL_InitSubSystem(SDL_INIT_AUDIO) } != 0 {
+        println!("SDL audio could not be initialised! ({:?})", sdl_error());
+        return Err(());
+    }
+
     let mut audio_settings = SDL_AudioSpec {
         freq: samples_per_second,
         format: AUDIO_S16LSB,
         channels: 2,
         silence: 0,
-        samples: 512,
+        samples: AUDIO_DEVICE_FRAMES,
         padding: 0,
         size: 0,
         callback: audio_callback,
-        userdata: ring_buffer as *mut _ as *mut c_void,
+        userdata: ring.as_user_data(),
     };
 
-    ring_buffer.size = buffer_size;
-    ring_buffer.data = unsafe {
-        mmap(ptr::null_mut(),
-             buffer_size as usize,
-             PROT_READ | PROT_WRITE,
-             MAP_PRIVATE | MAP_ANON,
-             -1,
-             0) as *mut u8
-    };
-    ring_buffer.play_cursor = 0;
-    ring_buffer.write_cursor = 0;
+    // Without an obtained spec SDL converts to exactly what was asked for
+    if unsafe { SDL_OpenAudio(&mut audio_settings, ptr::null_mut()) } != 0 {
+        println!("The audio device could not be opened! ({:?})", sdl_error());
+        return Err(());
+    }
 
-    if ring_buffer.data.is_null() {
-        panic!("Audio buffer could not be optained!");
+    if audio_settings.format != AUDIO_S16LSB {
+        println!("Audio buffer format can not be used!");
+        unsafe {
+            SDL_CloseAudio();
+        }
+        return Err(());
     }
 
     unsafe {
-        SDL_OpenAudio(&mut audio_settings, ptr::null_mut());
+        SDL_PauseAudio(0);
     }
+    Ok(())
+}
 
-    if audio_settings.format != AUDIO_S16LSB {
-        panic!("Audio buffer format can not be used!");
+fn start_sink(samples_per_second: i32,
+              ring: &Arc<AudioRing>,
+              file_path: Option<&PathBuf>)
+              -> AudioOutput {
+    let file = file_path.and_then(|path| {
+        match wav::create(path, samples_per_second as u32) {
+            Ok(wav_file) => Some(wav_file),
+            Err(error) => {
+                println!("The audio output file could not be created, discarding the audio! \
+                          ({:?}: {})",
+                         path,
+                         error);
+                None
+            }
+        }
+    });
+
+    AudioOutput::Sink(RealtimeSink::start(ring.clone(),
+                                          samples_per_second as u32,
+                                          AUDIO_DEVICE_FRAMES as usize,
+                                          performance_counter,
+                                          file))
+}
+
+fn init_audio(samples_per_second: i32, ring: &Arc<AudioRing>, options: &Options) -> AudioOutput {
+    match options.audio_backend {
+        AudioBackend::Sdl => {
+            match init_sdl_audio(samples_per_second, ring) {
+                Ok(()) => AudioOutput::Sdl,
+                Err(()) => {
+                    println!("Falling back to the null audio backend!");
+                    start_sink(samples_per_second, ring, None)
+                }
+            }
+        }
+        AudioBackend::Null => start_sink(samples_per_second, ring, None),
+        AudioBackend::File => start_sink(samples_per_second, ring, options.audio_file.as_ref()),
+    }
+}
+
+fn close_audio(output: AudioOutput) {
+    match output {
+        AudioOutput::Sdl => unsafe { SDL_CloseAudio() },
+        AudioOutput::Sink(sink) => sink.stop(),
     }
 }
 
@@ -479,17 +650,112 @@ fn resize_texture(renderer: *mut SDL_Renderer, buffer: &mut BackBuffer, width: i
     buffer.size = size;
 }
 
+// Where in a window of the given size the backbuffer ends up
+fn backbuffer_rect(buffer: &BackBuffer, window_width: i32, window_height: i32) -> SDL_Rect {
+    if buffer.scaling == Scaling::Resize || buffer.width <= 0 || buffer.height <= 0 {
+        return SDL_Rect {
+            x: 0,
+            y: 0,
+            w: window_width,
+            h: window_height,
+        };
+    }
+
+    let integer_scale = cmp::min(window_width / buffer.width, window_height / buffer.height);
+    let (width, height) = if buffer.scaling == Scaling::Integer && integer_scale >= 1 {
+        (buffer.width * integer_scale, buffer.height * integer_scale)
+    } else if window_width as i64 * buffer.height as i64 >
+              window_height as i64 * buffer.width as i64 {
+        // Window is wider than the backbuffer, bars left and right
+        ((window_height as i64 * buffer.width as i64 / buffer.height as i64) as i32,
+         window_height)
+    } else {
+        (window_width,
+         (window_width as i64 * buffer.height as i64 / buffer.width as i64) as i32)
+    };
+
+    SDL_Rect {
+        x: (window_width - width) / 2,
+        y: (window_height - height) / 2,
+        w: width,
+        h: height,
+    }
+}
+
 fn update_window(renderer: *mut SDL_Renderer, buffer: &mut BackBuffer) {
+    let mut output_width = 0;
+    let mut output_height = 0;
+    unsafe {
+        SDL_GetRendererOutputSize(renderer, &mut output_width, &mut output_height);
+    }
+    let dest = backbuffer_rect(buffer, output_width, output_height);
+
     unsafe {
         SDL_UpdateTexture(buffer.texture,
                           ptr::null(),
                           buffer.pixels as *const _,
                           buffer.texture_width * BYTES_PER_PIXEL as i32);
-        SDL_RenderCopy(renderer, buffer.texture, ptr::null(), ptr::null());
+        // Letterbox bars
+        SDL_SetRenderDrawColor(renderer, 0, 0, 0, 255);
+        SDL_RenderClear(renderer);
+        SDL_RenderCopy(renderer, buffer.texture, ptr::null(), &dest);
         SDL_RenderPresent(renderer);
     }
 }
 
+// Borderless fullscreen on the desktop resolution, the backbuffer scaling
+// takes care of the new window size like for any other resize
+fn toggle_fullscreen(window: *mut SDL_Window) {
+    let flags = unsafe { SDL_GetWindowFlags(window) };
+    let new_flags = if (flags & SDL_WINDOW_FULLSCREEN) != 0 {
+        0
+    } else {
+        SDL_WINDOW_FULLSCREEN_DESKTOP
+    };
+    if unsafe { SDL_SetWindowFullscreen(window, new_flags) } != 0 {
+        println!("Switching fullscreen failed!");
+    }
+}
+
+// Window coordinates are mapped back through the letterboxing and scaling
+// into backbuffer pixels. Positions on the bars end up outside of the
+// backbuffer, the game has to clip them like any other position.
+fn window_to_backbuffer(window_id: u32, buffer: &BackBuffer, x: i32, y: i32) -> (i32, i32) {
+    let mut window_width = 0;
+    let mut window_height = 0;
+    unsafe {
+        SDL_GetWindowSize(SDL_GetWindowFromID(window_id),
+                          &mut window_width,
+                          &mut window_height);
+    }
+
+    let rect = backbuffer_rect(buffer, window_width, window_height);
+    if rect.w <= 0 || rect.h <= 0 {
+        (x, y)
+    } else {
+        (((x - rect.x) as i64 * buffer.width as i64 / rect.w as i64) as i32,
+         ((y - rect.y) as i64 * buffer.height as i64 / rect.h as i64) as i32)
+    }
+}
+
+fn carry_over_mouse(old_input: &Input, new_input: &mut Input) {
+    new_input.mouse_x = old_input.mouse_x;
+    new_input.mouse_y = old_input.mouse_y;
+    new_input.mouse_z = old_input.mouse_z;
+
+    new_input.mouse_l = old_input.mouse_l;
+    new_input.mouse_r = old_input.mouse_r;
+    new_input.mouse_m = old_input.mouse_m;
+    new_input.mouse_x1 = old_input.mouse_x1;
+    new_input.mouse_x2 = old_input.mouse_x2;
+
+    new_input.mouse_l.half_transitions = 0;
+    new_input.mouse_r.half_transitions = 0;
+    new_input.mouse_m.half_transitions = 0;
+    new_input.mouse_x1.half_transitions = 0;
+    new_input.mouse_x2.half_transitions = 0;
+}
+
 fn process_key_press(button: &mut Button, is_down: bool) {
     debug_assert_ne!(button.ended_down, is_down);
     button.ended_down = is_down;
@@ -498,7 +764,11 @@ fn process_key_press(button: &mut Button, is_down: bool) {
 
 fn handle_event(event: &SDL_Event,
                 buffer: &mut BackBuffer,
-                keyboard: &mut ControllerInput)
+                input: &mut Input,
+                controllers: &mut [*mut SDL_GameController; MAX_CONTROLLERS as usize],
+                bindings: &Bindings,
+                replay: &mut Option<Replay>,
+                dumper: &mut FrameDumper)
                 -> bool {
 
     let mut keep_running = true;
@@ -511,7 +781,13 @@ fn handle_event(event: &SDL_Event,
             let window_event = event.window_event();
             let renderer = get_renderer_from_window_id(window_event.windowID);
             match window_event.event {
-                SDL_WINDOWEVENT_SIZE_CHANGED => {}
+                SDL_WINDOWEVENT_SIZE_CHANGED => {
+                    if buffer.scaling == Scaling::Resize && window_event.data1 > 0 &&
+                       window_event.data2 > 0 {
+                        resize_texture(renderer, buffer, window_event.data1, window_event.data2);
+                    }
+                    update_window(renderer, buffer);
+                }
 
                 SDL_WINDOWEVENT_EXPOSED => {
                     update_window(renderer, buffer);
@@ -523,35 +799,104 @@ fn handle_event(event: &SDL_Event,
         SDL_KEYDOWN |
         SDL_KEYUP => {
             let keyboard_event = event.keyboard_event();
-            let code = keyboard_event.keysym.sym;
+            let scancode = keyboard_event.keysym.scancode;
             let is_down = keyboard_event.state == SDL_PRESSED;
             let was_down = (keyboard_event.state != SDL_PRESSED) ||
                               (keyboard_event.repeat != 0);
             if was_down != is_down {
+                let keyboard = &mut input.controllers[0];
                 let alt_key_down = (keyboard_event.keysym._mod & KMOD_ALT) != 0;
-                match code {
-                    SDLK_F4 => {
-                        if alt_key_down {
-                            keep_running = false;
+                // Matched by position like the bindings, see RESERVED_KEYS
+                match scancode {
+                    SDL_SCANCODE_F4 if alt_key_down => keep_running = false,
+                    SDL_SCANCODE_RETURN if alt_key_down => {
+                        if is_down {
+                            toggle_fullscreen(unsafe {
+                                SDL_GetWindowFromID(keyboard_event.windowID)
+                            });
+                        }
+                    }
+                    SDL_SCANCODE_L => {
+                        if is_down {
+                            match *replay {
+                                Some(ref mut replay) => replay.cycle(),
+                                None => println!("Input recording is not available!"),
+                            }
+                        }
+                    }
+                    SDL_SCANCODE_F12 => {
+                        if is_down {
+                            dumper.request_capture();
                         }
                     }
-                    SDLK_w => process_key_press(&mut keyboard.move_up, is_down),
-                    SDLK_a => process_key_press(&mut keyboard.move_left, is_down),
-                    SDLK_s => process_key_press(&mut keyboard.move_down, is_down),
-                    SDLK_d => process_key_press(&mut keyboard.move_right, is_down),
-                    SDLK_q => process_key_press(&mut keyboard.left_shoulder, is_down),
-                    SDLK_e => process_key_press(&mut keyboard.right_shoulder, is_down),
-                    SDLK_UP => process_key_press(&mut keyboard.action_up, is_down),
-                    SDLK_LEFT => process_key_press(&mut keyboard.action_left, is_down),
-                    SDLK_RIGHT => process_key_press(&mut keyboard.action_right, is_down),
-                    SDLK_DOWN => process_key_press(&mut keyboard.action_down, is_down),
-                    SDLK_ESCAPE => process_key_press(&mut keyboard.back, is_down),
-                    SDLK_SPACE => process_key_press(&mut keyboard.start, is_down),
-                    _ => (),
+                    _ => {
+                        // With several keys bound to the same button only
+                        // the changes of the button itself count
+                        bindings.for_each_bound_key(scancode, |logical| {
+                            let button = logical.button_mut(keyboard);
+                            if button.ended_down != is_down {
+                                process_key_press(button, is_down)
+                            }
+                        })
+                    }
                 }
             }
         }
 
+        SDL_MOUSEMOTION => {
+            let motion_event = event.mouse_motion_event();
+            let (x, y) = window_to_backbuffer(motion_event.windowID,
+                                              buffer,
+                                              motion_event.x,
+                                              motion_event.y);
+            input.mouse_x = x;
+            input.mouse_y = y;
+        }
+
+        SDL_MOUSEBUTTONDOWN |
+        SDL_MOUSEBUTTONUP => {
+            let button_event = event.mouse_button_event();
+            let is_down = button_event.state == SDL_PRESSED;
+            let (x, y) = window_to_backbuffer(button_event.windowID,
+                                              buffer,
+                                              button_event.x,
+                                              button_event.y);
+            input.mouse_x = x;
+            input.mouse_y = y;
+            let button = match button_event.button {
+                SDL_BUTTON_LEFT => Some(&mut input.mouse_l),
+                SDL_BUTTON_MIDDLE => Some(&mut input.mouse_m),
+                SDL_BUTTON_RIGHT => Some(&mut input.mouse_r),
+                SDL_BUTTON_X1 => Some(&mut input.mouse_x1),
+                SDL_BUTTON_X2 => Some(&mut input.mouse_x2),
+                _ => None,
+            };
+            // A button pressed outside of the window only shows up with its
+            // release, and a release can get lost while the focus is gone
+            if let Some(button) = button {
+                if button.ended_down != is_down {
+                    process_key_press(button, is_down);
+                }
+            }
+        }
+
+        // mouse_z is the accumulated wheel position just like x and y are
+        // absolute positions
+        SDL_MOUSEWHEEL => {
+            let wheel_event = event.mouse_wheel_event();
+            input.mouse_z += wheel_event.y;
+        }
+
+        SDL_CONTROLLERDEVICEADDED => {
+            let device_event = event.controller_device_event();
+            open_controller(controllers, device_event.which);
+        }
+
+        SDL_CONTROLLERDEVICEREMOVED => {
+            let device_event = event.controller_device_event();
+            close_controller(controllers, device_event.which);
+        }
+
         _ => (),
     }
 
@@ -565,48 +910,176 @@ fn handle_event(event: &SDL_Event,
     keep_running
 }
 
-fn open_controllers(controllers: &mut [*mut SDL_GameController; MAX_CONTROLLERS as usize]) -> i32 {
-    let mut controller_num = 0;
-    for controller_idx in 0..unsafe { SDL_NumJoysticks() } {
-        if controller_num >= MAX_CONTROLLERS {
-            break;
-        }
-        if unsafe { SDL_IsGameController(controller_idx) } == SDL_bool::SDL_TRUE {
-            controllers[controller_num as usize] = unsafe {
-                SDL_GameControllerOpen(controller_num)
-            };
-            controller_num += 1;
+// SDL sends a device added event for every controller that is already
+// plugged in at startup as well so this is the only place controllers get
+// opened.
+fn open_controller(controllers: &mut [*mut SDL_GameController; MAX_CONTROLLERS as usize],
+                   device_index: c_int) {
+    if unsafe { SDL_IsGameController(device_index) } != SDL_bool::SDL_TRUE {
+        return;
+    }
+
+    let controller = unsafe { SDL_GameControllerOpen(device_index) };
+    if controller.is_null() {
+        return;
+    }
+
+    // Opening an already opened controller only bumps its reference count
+    if controllers.iter().any(|&open| open == controller) {
+        unsafe {
+            SDL_GameControllerClose(controller);
         }
+        return;
+    }
 
+    match controllers.iter_mut().find(|slot| slot.is_null()) {
+        Some(slot) => *slot = controller,
+        None => unsafe {
+            // No free slot left for another controller
+            SDL_GameControllerClose(controller);
+        },
     }
-    controller_num
 }
 
-fn close_controllers(controllers: [*mut SDL_GameController; MAX_CONTROLLERS as usize],
-                     controller_num: i32) {
-    for controller_idx in 0..controller_num {
-        let controller = controllers[controller_idx as usize];
-        if !controller.is_null() {
+fn close_controller(controllers: &mut [*mut SDL_GameController; MAX_CONTROLLERS as usize],
+                    instance_id: SDL_JoystickID) {
+    for slot in controllers.iter_mut() {
+        if !slot.is_null() &&
+           unsafe { SDL_JoystickInstanceID(SDL_GameControllerGetJoystick(*slot)) } ==
+           instance_id {
             unsafe {
-                SDL_GameControllerClose(controller);
+                SDL_GameControllerClose(*slot);
             }
+            *slot = ptr::null_mut();
         }
     }
 }
 
-fn get_exe_path() -> PathBuf {
-    read_link("/proc/self/exe").unwrap()
+fn close_controllers(controllers: &mut [*mut SDL_GameController; MAX_CONTROLLERS as usize]) {
+    for slot in controllers.iter_mut() {
+        if !slot.is_null() {
+            unsafe {
+                SDL_GameControllerClose(*slot);
+            }
+            *slot = ptr::null_mut();
+        }
+    }
 }
 
-fn compare_file_time(time1: &linux::timespec, time2: &linux::timespec) -> TimeComp {
-    if (time1.tv_sec == time2.tv_sec) && (time1.tv_nsec == time2.tv_nsec) {
-        TimeComp::Same
-    } else if (time1.tv_sec > time2.tv_sec) ||
-       ((time1.tv_sec == time2.tv_sec) && (time1.tv_nsec > time2.tv_nsec)) {
-        TimeComp::Earlier
+fn initialize_replay(exe_dirname: &PathBuf,
+                     permanent_size: usize,
+                     transient_size: usize,
+                     delta_t: f32,
+                     game_memory: &GameMemoryBlock,
+                     keep_snapshot: bool)
+                     -> Result<Replay, ()> {
+    let mut result: Result<Replay, ()> = Err(());
+    let file_size = permanent_size + transient_size;
+
+    let mut mmap_path = exe_dirname.clone();
+    mmap_path.push("mmap.rhm");
+
+    let mut input_path = exe_dirname.clone();
+    input_path.push("input.rhi");
+    let checksum_path = input_path.with_extension("rhc");
+
+    // The snapshot of an earlier session is needed to play its recording back
+    let flags = if keep_snapshot {
+        O_RDWR | O_CREAT
     } else {
-        TimeComp::Later
+        O_RDWR | O_CREAT | O_TRUNC
+    };
+    let mmap_name = CString::new(mmap_path.to_str().unwrap()).unwrap();
+    let file_handle = unsafe {
+        open(mmap_name.as_ptr(), flags, S_IRUSR | S_IWUSR | S_IRGRP | S_IROTH)
+    };
+
+    if file_handle != -1 {
+        if unsafe { ftruncate(file_handle, file_size as off_t) } != -1 {
+            let address = unsafe {
+                mmap(ptr::null_mut(),
+                     file_size as size_t,
+                     PROT_READ | PROT_WRITE,
+                     MAP_SHARED,
+                     file_handle,
+                     0)
+            };
+
+            if address != MAP_FAILED {
+                result = Ok(Replay {
+                    input_path: input_path,
+                    recorder: None,
+                    player: None,
+                    checksum_path: checksum_path,
+                    checksum_writer: None,
+                    checksums: Vec::new(),
+                    differing_frames: 0,
+                    permanent: game_memory.permanent,
+                    transient: game_memory.transient,
+                    memory: address,
+                    permanent_size: permanent_size,
+                    transient_size: transient_size,
+                    delta_t: delta_t,
+                    state: ReplayState::Nothing,
+                });
+            } else {
+                println!("The replay snapshot could not be mapped! ({:?})", mmap_path);
+            }
+        } else {
+            println!("The replay snapshot could not be resized! ({:?})", mmap_path);
+        }
+        // The mapping keeps the file alive on its own
+        unsafe {
+            close(file_handle);
+        }
+    } else {
+        println!("The replay snapshot could not be created! ({:?})", mmap_path);
     }
+    result
+}
+
+fn log_input(replay: &mut Replay, input: &Input) {
+    let failed = match replay.recorder.as_mut() {
+        Some(recorder) => recorder.write_input(input).is_err(),
+        None => false,
+    };
+    if failed {
+        println!("Writing the input recording failed, recording stopped!");
+        replay.stop_recording();
+    }
+}
+
+// Returns true when the playback wrapped around to the first frame again
+fn override_input(replay: &mut Replay, input: &mut Input) -> bool {
+    let mut looped = false;
+    let read = match replay.player.as_mut() {
+        Some(player) => {
+            match player.read_input(input) {
+                Ok(false) => {
+                    // Restart from the snapshot to loop the recorded section
+                    looped = true;
+                    player.rewind().and_then(|_| player.read_input(input))
+                }
+                result => result,
+            }
+        }
+        None => return false,
+    };
+
+    match read {
+        Ok(true) => {
+            if looped {
+                replay.restore_snapshot();
+            }
+        }
+        // Nothing was recorded at all
+        Ok(false) => replay.stop_replay(),
+        Err(error) => {
+            println!("Reading the input recording failed! ({})", error);
+            replay.stop_replay();
+        }
+    }
+    looped
 }
 
 fn get_seconds_elapsed(old_counter: u64, new_counter: u64, frequency: u64) -> f32 {
@@ -625,20 +1098,91 @@ fn get_window_refresh_rate(window: *mut SDL_Window) -> c_int {
     }
 }
 
+// Sets the game memory up from the crash dump given on the command line.
+// Replaying returns the recorded input that leads up to the crash.
+fn load_crash_dump(options: &Options,
+                   game_memory: &mut GameMemory)
+                   -> Option<RecordingReader<File>> {
+    let (dir, replaying) = match (&options.resume_crash, &options.replay_crash) {
+        (&Some(ref dir), _) => (dir, false),
+        (_, &Some(ref dir)) => (dir, true),
+        _ => return None,
+    };
+
+    let result = crash::read_info(dir).and_then(|info| {
+        if replaying {
+            let player = crash::open_input(dir)?;
+            crash::load_memory(dir, crash::SNAPSHOT_FILE, game_memory.permanent)?;
+            game_memory.initialized = info.snapshot_initialized;
+            println!("Replaying {} frames from frame {}, the crash happened at frame {}",
+                     player.header().frame_count,
+                     info.snapshot_frame,
+                     info.frame);
+            Ok(Some(player))
+        } else {
+            crash::load_memory(dir, crash::MEMORY_FILE, game_memory.permanent)?;
+            game_memory.initialized = info.memory_initialized;
+            println!("Resuming at frame {} where the crash happened", info.frame);
+            Ok(None)
+        }
+    });
+
+    match result {
+        Ok(player) => {
+            game_memory.restored = game_memory.initialized;
+            player
+        }
+        Err(error) => {
+            println!("The crash dump could not be loaded! ({:?}: {})", dir, error);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_options() -> Options {
+    match options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(ParseError::Help) => {
+            println!("{}", options::USAGE);
+            process::exit(0);
+        }
+        Err(ParseError::Invalid(message)) => {
+            println!("{}", message);
+            println!("{}", options::USAGE);
+            process::exit(2);
+        }
+    }
+}
+
 pub fn linuxmain() {
-    if unsafe { SDL_Init(SDL_INIT_VIDEO | SDL_INIT_GAMECONTROLLER | SDL_INIT_AUDIO) != 0 } {
+    let options = parse_options();
+
+    if let Some(ref asset_dir) = options.asset_dir {
+        if let Err(error) = env::set_current_dir(asset_dir) {
+            println!("Couldn't change into the asset directory! ({:?}: {})", asset_dir, error);
+            process::exit(1);
+        }
+    }
+
+    // Audio is initialised on its own, the game has to run without a device
+    if unsafe { SDL_Init(SDL_INIT_VIDEO | SDL_INIT_GAMECONTROLLER) != 0 } {
         panic!("SDL initialisation failed!");
     }
 
 
     let window_title = CString::new("Rust Hero").unwrap();
+    let window_flags = if options.fullscreen {
+        SDL_WINDOW_RESIZABLE | SDL_WINDOW_FULLSCREEN_DESKTOP
+    } else {
+        SDL_WINDOW_RESIZABLE
+    };
     let window: *mut SDL_Window = unsafe {
         SDL_CreateWindow(window_title.as_ptr(),
                          SDL_WINDOWPOS_UNDEFINED,
                          SDL_WINDOWPOS_UNDEFINED,
-                         WINDOW_WIDTH,
-                         WINDOW_HEIGHT,
-                         SDL_WINDOW_RESIZABLE)
+                         options.window_width,
+                         options.window_height,
+                         window_flags)
     };
     if !window.is_null() {
         let renderer: *mut SDL_Renderer = unsafe { SDL_CreateRenderer(window, -1, 0) };
@@ -654,35 +1198,27 @@ pub fn linuxmain() {
             height: 0,
             texture: ptr::null_mut(),
             texture_width: 0,
+            scaling: options.scaling,
         };
-        resize_texture(renderer, &mut buffer, WINDOW_WIDTH, WINDOW_HEIGHT);
+        resize_texture(renderer, &mut buffer, options.window_width, options.window_height);
 
         let monitor_refresh_rate = get_window_refresh_rate(window);
-        let game_refresh_rate = monitor_refresh_rate / 2;
+        let game_refresh_rate = match options.frame_rate {
+            Some(rate) => rate as c_int,
+            None => monitor_refresh_rate / 2,
+        };
         let target_seconds_per_frame = 1.0 / game_refresh_rate as f32;
 
         let mut controllers = [ptr::null_mut::<SDL_GameController>(); MAX_CONTROLLERS as usize];
 
-        let controller_num = open_controllers(&mut controllers);
-
-        let mut sound_output = SdlSoundOutput {
-            running_sample_idx: 0,
-            secondary_buffer_size: SAMPLES_PER_SECOND * BYTES_PER_SAMPLE,
-            latency_sample_count: SAMPLES_PER_SECOND / 15,
-        };
-        let mut ring_buffer = SdlAudioRingBuffer {
-            size: 0,
-            write_cursor: 0,
-            play_cursor: 0,
-            data: ptr::null_mut(),
-        };
-        init_audio(SAMPLES_PER_SECOND,
-                   sound_output.secondary_buffer_size,
-                   &mut ring_buffer);
-
-        unsafe {
-            SDL_PauseAudio(0);
-        }
+        // One second of audio, the ring has to outlive the audio output
+        let audio_ring = Arc::new(AudioRing::new(SAMPLES_PER_SECOND as usize));
+        let audio_output = init_audio(SAMPLES_PER_SECOND, &audio_ring, &options);
+        let mut audio_latency = AudioLatency::new(SAMPLES_PER_SECOND as u32,
+                                                  game_refresh_rate as u32,
+                                                  AUDIO_DEVICE_FRAMES as usize,
+                                                  options.audio_latency_ms,
+                                                  &audio_ring);
         let mut sound_samples: &mut [i16] = unsafe {
             // Allocation implicitly freed at the end of the execution
             let data = mmap(ptr::null_mut(),
@@ -699,38 +1235,41 @@ pub fn linuxmain() {
                                       ((SAMPLES_PER_SECOND * BYTES_PER_SAMPLE) / 2) as usize)
         };
 
-        let base_address = if cfg!(ndebug) {
-            0
-        } else {
-            util::tera_bytes(2)
-        };
-        let permanent_store_size = util::mega_bytes(64);
-        let transient_store_size = util::giga_bytes(1);
-        let total_size = permanent_store_size + transient_store_size;
-
-        let memory = unsafe {
-            mmap(base_address as *mut c_void,
-                 total_size as usize,
-                 PROT_READ | PROT_WRITE,
-                 MAP_PRIVATE | MAP_ANON,
-                 -1,
-                 0)
-        };
-
-        if memory.is_null() {
-            panic!("Memory for the Game could not be obtained!");
-        }
+        let permanent_store_size = options.permanent_size;
+        let transient_store_size = options.transient_size;
+        let memory = allocate_game_memory(permanent_store_size,
+                                          transient_store_size,
+                                          options.huge_pages)
+                         .unwrap_or_else(|_| process::exit(1));
+
+        let high_priority_threads = high_priority_thread_count();
+        // The crash handlers need a signal stack on every thread
+        let thread_setup: Option<fn()> = Some(crash::install_signal_stack);
+        let high_priority_queue = work_queue::create_work_queue(high_priority_threads,
+                                                                1,
+                                                                thread_setup);
+        let low_priority_queue = work_queue::create_work_queue(LOW_PRIORITY_THREADS,
+                                                               high_priority_threads + 1,
+                                                               thread_setup);
 
         let mut game_memory: GameMemory = GameMemory {
             initialized: false,
-            permanent: unsafe { slice::from_raw_parts_mut(memory as *mut u8, permanent_store_size) },
-            transient: unsafe {
-                slice::from_raw_parts_mut((memory as *mut u8).offset(permanent_store_size as isize),
-                                          transient_store_size)
-            },
+            restored: false,
+            permanent: unsafe { slice::from_raw_parts_mut(memory.permanent, permanent_store_size) },
+            transient: unsafe { slice::from_raw_parts_mut(memory.transient, transient_store_size) },
             platform_read_entire_file: debug::platform_read_entire_file,
             platform_write_entire_file: debug::platform_write_entire_file,
             platform_free_file_memory: debug::platform_free_file_memory,
+            platform_open_file: file::platform_open_file,
+            platform_read_file: file::platform_read_file,
+            platform_close_file: file::platform_close_file,
+            platform_list_files: file::platform_list_files,
+            platform_log: logging::platform_log,
+            high_priority_queue: high_priority_queue,
+            low_priority_queue: low_priority_queue,
+            platform_add_work_entry: work_queue::platform_add_work_entry,
+            platform_complete_all_work: work_queue::platform_complete_all_work,
+            frame_stats: FrameStats::new(target_seconds_per_frame),
         };
 
         let frequency = unsafe { SDL_GetPerformanceFrequency() };
@@ -738,32 +1277,112 @@ pub fn linuxmain() {
         let mut exe_dirname = get_exe_path();
         exe_dirname.pop();
 
-        let mut game_so_path = exe_dirname.clone();
-        game_so_path.push("deps/libgame.so");
-
-        let mut temp_so_path = exe_dirname.clone();
-        temp_so_path.push("deps/libgame_temp.so");
-
-        let game_so_string = CString::new(game_so_path.to_str().unwrap()).unwrap();
-        let temp_so_string = CString::new(temp_so_path.to_str().unwrap()).unwrap();
+        // Without a snapshot file there is no recording, the game still
+        // runs on live input
+        let mut replay = initialize_replay(&exe_dirname,
+                                           permanent_store_size,
+                                           transient_store_size,
+                                           target_seconds_per_frame,
+                                           &memory,
+                                           options.dump_playback)
+                             .map_err(|_| println!("Input recording is disabled!"))
+                             .ok();
+
+        let dump_dir = options.dump_dir.clone().unwrap_or_else(|| {
+            let mut dir = exe_dirname.clone();
+            dir.push("frames");
+            dir
+        });
+        let mut dumper = FrameDumper::new(dump_dir, options.dump_playback);
+
+        let mut audio_capture = options.capture_audio.as_ref().and_then(|path| {
+            match wav::create(path, SAMPLES_PER_SECOND as u32) {
+                Ok(wav_file) => Some(wav_file),
+                Err(error) => {
+                    println!("The audio capture could not be created! ({:?}: {})", path, error);
+                    None
+                }
+            }
+        });
+
+        let mut log_path = exe_dirname.clone();
+        log_path.push("rust_hero.log");
+        logging::init(log_path);
+
+        let mut crash_replay = load_crash_dump(&options, &mut game_memory);
+        let crash_dir = options.crash_dir.clone().unwrap_or_else(|| {
+            let mut dir = exe_dirname.clone();
+            dir.push("crashes");
+            dir
+        });
+        crash::install(crash_dir,
+                       game_memory.permanent.as_ptr(),
+                       permanent_store_size,
+                       transient_store_size,
+                       target_seconds_per_frame,
+                       game_memory.initialized);
+
+        let mut bindings_path = exe_dirname.clone();
+        bindings_path.push("bindings.cfg");
+        let mut bindings = Bindings::load(bindings_path);
+
+        let (game_so_string, temp_dir) = match options.game_library {
+            Some(ref path) => game_so_names_for(path),
+            None => get_game_so_names(),
+        };
+        let mut reloader = GameReloader::new(game_so_string, temp_dir);
 
-        let mut game = load_game_functions(&game_so_string, &temp_so_string);
+        let mut game = reloader.try_load().unwrap_or_else(Game::stub);
 
-        let thread_context = ThreadContext;
+        let thread_context = ThreadContext { index: 0 };
 
         let mut new_input: &mut Input = &mut Default::default();
         let mut old_input: &mut Input = &mut Default::default();
 
+        // The snapshot comes from an earlier session, the game has to
+        // reload its assets just like after restoring a crash dump
+        if dumper.dumps_playback() {
+            if let Some(ref mut replay) = replay {
+                if replay.start_replay() {
+                    game_memory.initialized = true;
+                    game_memory.restored = true;
+                }
+            }
+        }
+
         let mut last_counter = unsafe { SDL_GetPerformanceCounter() };
         let mut running = true;
         while running {
 
             new_input.delta_t = target_seconds_per_frame;
+            logging::set_frame(game_memory.frame_stats.frame_count);
+
+            if reloader.library_changed() {
+                if let Some(new_game) = reloader.try_load() {
+                    complete_all_game_work(&thread_context, &game_memory);
+
+                    // A different GameState layout can't be reinterpreted,
+                    // start the game over instead of crashing into it
+                    if new_game.abi.game_state_size != game.abi.game_state_size &&
+                       !game.handle.is_null() {
+                        println!("The game state layout changed, resetting the game memory!");
+                        if let Some(ref mut replay) = replay {
+                            if replay.is_recording() {
+                                replay.stop_recording();
+                            }
+                            if replay.is_replaying() {
+                                replay.stop_replay();
+                            }
+                        }
+                        reset_game_memory(&mut game_memory);
+                    }
+                    unload_game_functions(&mut game);
+                    game = new_game;
+                }
+            }
 
-            let new_write_time = get_last_write_time(&game_so_string);
-            if compare_file_time(&game.write_time, &new_write_time) != TimeComp::Earlier {
-                unload_game_functions(&mut game);
-                game = load_game_functions(&game_so_string, &temp_so_string);
+            if bindings.reload_if_changed() {
+                old_input.controllers[0] = Default::default();
             }
 
             let mut event: SDL_Event = Default::default();
@@ -771,52 +1390,32 @@ pub fn linuxmain() {
             new_input.controllers[0] = old_input.controllers[0];
             new_input.controllers[0].is_connected = true;
             new_input.controllers[0].zero_half_transitions();
+            carry_over_mouse(old_input, new_input);
             while unsafe { SDL_PollEvent(&mut event) } != 0 {
-                running = handle_event(&event, &mut buffer, &mut new_input.controllers[0]);
-            }
-
-            for controller in controllers.iter().take(controller_num as usize) {
-                if unsafe { SDL_GameControllerGetAttached(*controller) } ==
-                   SDL_bool::SDL_TRUE {
-
-                    let _up = unsafe {
-                        SDL_GameControllerGetButton(
-                            *controller,
-                            SDL_GameControllerButton::SDL_CONTROLLER_BUTTON_DPAD_UP) == 1
-                    };
-
-                    let _down = unsafe {
-                        SDL_GameControllerGetButton(
-                            *controller,
-                            SDL_GameControllerButton::SDL_CONTROLLER_BUTTON_DPAD_DOWN) == 1
-                    };
-                    let _left = unsafe {
-                        SDL_GameControllerGetButton(
-                            *controller,
-                            SDL_GameControllerButton::SDL_CONTROLLER_BUTTON_DPAD_LEFT) == 1
-                    };
-                    let _right = unsafe {
-                        SDL_GameControllerGetButton(
-                            *controller,
-                            SDL_GameControllerButton::SDL_CONTROLLER_BUTTON_DPAD_RIGHT) == 1
-                    };
-
-
-                    let _stick_x = unsafe {
-                        SDL_GameControllerGetAxis(
-                            *controller,
-                            SDL_GameControllerAxis::SDL_CONTROLLER_AXIS_LEFTX) == 1
-                    };
-                    let _stick_y = unsafe {
-                        SDL_GameControllerGetAxis(
-                            *controller,
-                            SDL_GameControllerAxis::SDL_CONTROLLER_AXIS_LEFTY) == 1
-                    };
+                // Don't let a later event in the same frame undo a quit request
+                if !handle_event(&event,
+                                 &mut buffer,
+                                 new_input,
+                                 &mut controllers,
+                                 &bindings,
+                                 &mut replay,
+                                 &mut dumper) {
+                    running = false;
+                }
+            }
+
+            // The 0 controller is the keyboard so the game controllers start at 1
+            for (index, controller) in controllers.iter().enumerate() {
+                let old_controller = &old_input.controllers[index + 1];
+                let new_controller = &mut new_input.controllers[index + 1];
+
+                if !controller.is_null() &&
+                   unsafe { SDL_GameControllerGetAttached(*controller) } == SDL_bool::SDL_TRUE {
+                    process_game_controller(*controller, &bindings, old_controller, new_controller);
                 } else {
-                    // The controller was plugged out so remove him from the
-                    // controllers list
-                    // If it is plugged in again query the SDL_ControllerEvent
-                    // with the corresponding eventID
+                    // Either the slot was never used or the controller was
+                    // plugged out and the removed event is still pending
+                    *new_controller = Default::default();
                 }
             }
 
@@ -830,42 +1429,84 @@ pub fn linuxmain() {
                 pitch: (buffer.width as i32) as usize,
             };
 
-            (game.update_and_render)(&thread_context, &mut game_memory, new_input, &mut video_buf);
+            // Replays override what the game sees, the next frame carries
+            // on from what was actually pressed
+            let live_input = *new_input;
 
-            unsafe {
-                SDL_LockAudio();
-            }
-            let byte_to_lock = (sound_output.running_sample_idx * BYTES_PER_SAMPLE) %
-                               sound_output.secondary_buffer_size;
-            let target_cursor = (ring_buffer.play_cursor +
-                                 (sound_output.latency_sample_count * BYTES_PER_SAMPLE)) %
-                                sound_output.secondary_buffer_size;
-            let bytes_to_write = if byte_to_lock > target_cursor {
-                sound_output.secondary_buffer_size - byte_to_lock + target_cursor
-            } else {
-                target_cursor - byte_to_lock
-            };
-            unsafe {
-                SDL_UnlockAudio();
+            if let Some(ref mut replay) = replay {
+                if replay.is_recording() {
+                    log_input(replay, new_input);
+                }
+
+                if replay.is_replaying() {
+                    let looped = override_input(replay, new_input);
+                    // One full pass over the recording is all that gets dumped
+                    if looped && dumper.dumps_playback() {
+                        replay.print_playback_check();
+                        break;
+                    }
+                }
             }
+            let replaying = replay.as_ref().map_or(false, Replay::is_replaying);
+            if dumper.dumps_playback() && !replaying {
+                println!("There is no input recording to dump!");
+                break;
+            }
+
+            if let Some(mut player) = crash_replay.take() {
+                match player.read_input(new_input) {
+                    Ok(true) => crash_replay = Some(player),
+                    Ok(false) => println!("The crash input is used up, continuing with live input"),
+                    Err(error) => println!("Reading the crash input failed! ({})", error),
+                }
+            }
+
+            crash::record_frame(new_input, game_memory.initialized);
+
+            let mut timing: FrameTiming = Default::default();
+
+            let update_counter = unsafe { SDL_GetPerformanceCounter() };
+            (game.update_and_render)(&thread_context, &mut game_memory, new_input, &mut video_buf);
+            timing.update = get_seconds_elapsed(update_counter,
+                                                unsafe { SDL_GetPerformanceCounter() },
+                                                frequency);
+
+            dumper.end_frame(video_buf.memory,
+                             video_buf.width,
+                             video_buf.height,
+                             video_buf.pitch,
+                             replay.as_ref().and_then(Replay::playback_frame));
+            if let Some(ref mut replay) = replay {
+                if replay.is_recording() || replay.is_replaying() {
+                    replay.check_frame(framedump::frame_checksum(video_buf.memory,
+                                                                 video_buf.width,
+                                                                 video_buf.height,
+                                                                 video_buf.pitch));
+                }
+            }
+
+            let audio_counter = unsafe { SDL_GetPerformanceCounter() };
+            let frames_to_write = audio_latency.frames_to_write(&audio_ring,
+                                                                audio_counter,
+                                                                frequency);
+            let sample_count = cmp::min(frames_to_write * audio::CHANNELS, sound_samples.len());
             let mut sound_buffer = SoundBuffer {
-                samples: &mut sound_samples[..bytes_to_write as usize / mem::size_of::<i16>()],
+                samples: &mut sound_samples[..sample_count],
                 samples_per_second: SAMPLES_PER_SECOND as u32,
             };
 
 
             (game.get_sound_samples)(&thread_context, &mut game_memory, &mut sound_buffer);
 
-            fill_sound_buffer(&mut sound_output,
-                              byte_to_lock,
-                              bytes_to_write,
-                              &sound_buffer,
-                              &mut ring_buffer);
+            fill_sound_buffer(&sound_buffer, &audio_ring, &mut audio_capture);
+            timing.sound = get_seconds_elapsed(audio_counter,
+                                               unsafe { SDL_GetPerformanceCounter() },
+                                               frequency);
 
 
-            let time_elapsed = get_seconds_elapsed(last_counter,
-                                                   unsafe { SDL_GetPerformanceCounter() },
-                                                   frequency);
+            let sleep_counter = unsafe { SDL_GetPerformanceCounter() };
+            let time_elapsed = get_seconds_elapsed(last_counter, sleep_counter, frequency);
+            timing.missed = time_elapsed >= target_seconds_per_frame;
             if time_elapsed < target_seconds_per_frame {
                 let sleep_time = ((target_seconds_per_frame - time_elapsed) * 1000.0) - 1.0;
                 if sleep_time as u32 > 0 {
@@ -882,15 +1523,39 @@ pub fn linuxmain() {
                 }
             }
 
-            last_counter = unsafe { SDL_GetPerformanceCounter() };
+            let end_counter = unsafe { SDL_GetPerformanceCounter() };
+            timing.sleep = get_seconds_elapsed(sleep_counter, end_counter, frequency);
+            timing.total = get_seconds_elapsed(last_counter, end_counter, frequency);
+            last_counter = end_counter;
 
 
             update_window(renderer, &mut buffer);
+            timing.present = get_seconds_elapsed(end_counter,
+                                                 unsafe { SDL_GetPerformanceCounter() },
+                                                 frequency);
+
+            // The game sees the frames up to the previous one
+            game_memory.frame_stats.push(timing);
+            *new_input = live_input;
             mem::swap(new_input, old_input);
         }
 
-        close_controllers(controllers, controller_num);
+        game_memory.frame_stats.print_report();
+
+        close_controllers(&mut controllers);
+        complete_all_game_work(&thread_context, &game_memory);
+        unload_game_functions(&mut game);
 
+        // Nothing may read from the ring anymore once it goes away
+        close_audio(audio_output);
+
+        if let Some(wav_file) = audio_capture.take() {
+            if let Err(error) = wav_file.finish() {
+                println!("The audio capture could not be finished! ({})", error);
+            }
+        }
+
+        crash::discard_dump();
     } else {
         // TODO: Window creation failed horribly just log it
     }
@@ -899,3 +1564,157 @@ pub fn linuxmain() {
         SDL_Quit();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use ffi::sdl::SDL_GameControllerAxis::*;
+    use ffi::sdl::SDL_GameControllerButton::*;
+
+    // Buttons and axes of the virtual pad are laid out in the order of the
+    // SDL enums, so their indices are the enum values
+    const VIRTUAL_PAD_MAPPING: &'static str = "Virtual pad,a:b0,b:b1,x:b2,y:b3,back:b4,guide:b5,\
+                                               start:b6,leftstick:b7,rightstick:b8,\
+                                               leftshoulder:b9,rightshoulder:b10,dpup:b11,\
+                                               dpdown:b12,dpleft:b13,dpright:b14,leftx:a0,\
+                                               lefty:a1,rightx:a2,righty:a3,lefttrigger:a4,\
+                                               righttrigger:a5";
+
+    struct VirtualPad {
+        device_index: c_int,
+        joystick: *mut SDL_Joystick,
+    }
+
+    impl VirtualPad {
+        fn attach(controllers: &mut [*mut SDL_GameController; MAX_CONTROLLERS as usize])
+                  -> VirtualPad {
+            unsafe {
+                let device_index = SDL_JoystickAttachVirtual(SDL_JOYSTICK_TYPE_GAMECONTROLLER,
+                                                             SDL_CONTROLLER_AXIS_MAX as c_int,
+                                                             SDL_CONTROLLER_BUTTON_MAX as c_int,
+                                                             0);
+                assert!(device_index >= 0, "{:?}", sdl_error());
+
+                let mut guid = [0 as c_char; 33];
+                SDL_JoystickGetGUIDString(SDL_JoystickGetDeviceGUID(device_index),
+                                          guid.as_mut_ptr(),
+                                          guid.len() as c_int);
+                let guid = CStr::from_ptr(guid.as_ptr()).to_str().unwrap();
+                let mapping = CString::new(format!("{},{}", guid, VIRTUAL_PAD_MAPPING)).unwrap();
+                assert!(SDL_GameControllerAddMapping(mapping.as_ptr()) >= 0,
+                        "{:?}",
+                        sdl_error());
+
+                // Goes through the same path as a pad plugged in mid-session
+                open_controller(controllers, device_index);
+                assert!(!controllers[0].is_null());
+
+                VirtualPad {
+                    device_index: device_index,
+                    joystick: SDL_GameControllerGetJoystick(controllers[0]),
+                }
+            }
+        }
+
+        fn set_stick(&self, x: i16, y: i16) {
+            unsafe {
+                SDL_JoystickSetVirtualAxis(self.joystick, SDL_CONTROLLER_AXIS_LEFTX as c_int, x);
+                SDL_JoystickSetVirtualAxis(self.joystick, SDL_CONTROLLER_AXIS_LEFTY as c_int, y);
+            }
+        }
+
+        fn set_button(&self, button: SDL_GameControllerButton, down: bool) {
+            let value = if down { SDL_PRESSED } else { SDL_RELEASED };
+            unsafe {
+                SDL_JoystickSetVirtualButton(self.joystick, button as c_int, value);
+            }
+        }
+    }
+
+    impl Drop for VirtualPad {
+        fn drop(&mut self) {
+            unsafe {
+                SDL_JoystickDetachVirtual(self.device_index);
+            }
+        }
+    }
+
+    // Hands the virtual pad state to SDL and maps it like a frame does
+    fn poll(controller: *mut SDL_GameController,
+            bindings: &Bindings,
+            old_controller: &ControllerInput)
+            -> ControllerInput {
+        unsafe {
+            SDL_GameControllerUpdate();
+        }
+        let mut new_controller = ControllerInput::default();
+        process_game_controller(controller, bindings, old_controller, &mut new_controller);
+        new_controller
+    }
+
+    #[test]
+    fn virtual_pad_maps_onto_controller_input() {
+        assert_eq!(unsafe { SDL_Init(SDL_INIT_GAMECONTROLLER) }, 0, "{:?}", sdl_error());
+
+        // No file, so the default pad bindings are used
+        let bindings = Bindings::load(env::temp_dir().join("rust_hero_no_bindings.cfg"));
+        let mut controllers = [ptr::null_mut::<SDL_GameController>(); MAX_CONTROLLERS as usize];
+        let pad = VirtualPad::attach(&mut controllers);
+        let controller = controllers[0];
+
+        // Inside the dead zone the stick reads as centered
+        pad.set_stick(CONTROLLER_DEAD_ZONE, -CONTROLLER_DEAD_ZONE);
+        let centered = poll(controller, &bindings, &ControllerInput::default());
+        assert!(centered.is_connected);
+        assert_eq!(centered.average_x, Some(0.0));
+        assert_eq!(centered.average_y, Some(0.0));
+        assert!(!centered.move_right.ended_down && !centered.move_up.ended_down);
+
+        // The range starts at the edge of the dead zone, up is positive
+        pad.set_stick(i16::max_value(), i16::min_value());
+        let pushed = poll(controller, &bindings, &centered);
+        assert_eq!(pushed.average_x, Some(1.0));
+        assert_eq!(pushed.average_y, Some(1.0));
+        assert!(pushed.move_right.ended_down && pushed.move_up.ended_down);
+        assert_eq!(pushed.move_right.half_transitions, 1);
+
+        pad.set_stick(CONTROLLER_DEAD_ZONE + 2000, 0);
+        let slight = poll(controller, &bindings, &pushed);
+        let x = slight.average_x.unwrap();
+        assert!(x > 0.0 && x < 0.1);
+        assert!(!slight.move_right.ended_down);
+        assert_eq!(slight.move_right.half_transitions, 1);
+
+        // The D-pad overrides the stick and drops the analog averages
+        pad.set_stick(i16::max_value(), 0);
+        pad.set_button(SDL_CONTROLLER_BUTTON_DPAD_LEFT, true);
+        let dpad = poll(controller, &bindings, &slight);
+        assert_eq!(dpad.average_x, None);
+        assert_eq!(dpad.average_y, None);
+        assert!(dpad.move_left.ended_down && !dpad.move_right.ended_down);
+        pad.set_button(SDL_CONTROLLER_BUTTON_DPAD_LEFT, false);
+
+        pad.set_button(SDL_CONTROLLER_BUTTON_A, true);
+        pad.set_button(SDL_CONTROLLER_BUTTON_LEFTSHOULDER, true);
+        pad.set_button(SDL_CONTROLLER_BUTTON_START, true);
+        let pressed = poll(controller, &bindings, &dpad);
+        assert!(pressed.action_down.ended_down && pressed.left_shoulder.ended_down);
+        assert!(pressed.start.ended_down && !pressed.back.ended_down);
+        assert_eq!(pressed.action_down.half_transitions, 1);
+
+        // Held down, so no new transition
+        let held = poll(controller, &bindings, &pressed);
+        assert!(held.action_down.ended_down);
+        assert_eq!(held.action_down.half_transitions, 0);
+
+        let instance_id = unsafe { SDL_JoystickInstanceID(pad.joystick) };
+        close_controller(&mut controllers, instance_id);
+        assert!(controllers[0].is_null());
+
+        drop(pad);
+        unsafe {
+            SDL_Quit();
+        }
+    }
+}
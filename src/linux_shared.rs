@@ -0,0 +1,547 @@
+// Platform code without any SDL in it, shared by the SDL platform layer
+// and the headless runner: file access, the game memory and loading the
+// game library.
+
+use libc::{mode_t, S_IRUSR, S_IWUSR, S_IXUSR};
+use libc::{open, close, mmap, munmap, MAP_PRIVATE, MAP_FAILED, MAP_ANON};
+use libc::{O_RDONLY, O_WRONLY, O_CREAT, O_TRUNC, fstat, stat};
+use libc::{unlink, sysconf, _SC_PAGESIZE};
+use libc::{mprotect, madvise, MADV_HUGEPAGE, MAP_NORESERVE, PROT_NONE, PROT_READ, PROT_WRITE};
+use std::io;
+use std::ptr;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::fs::read_link;
+use std::ffi::CString;
+
+use ffi::linux::{self, c_int, c_void};
+use common::{GetSoundSamplesT, UpdateAndRenderT, Input, SoundBuffer};
+use common::{VideoBuffer, GameMemory, ThreadContext};
+use common::{GameAbi, GetGameAbiT, GAME_ABI_VERSION};
+
+pub const S_IRGRP: mode_t = 32;
+pub const S_IROTH: mode_t = 4;
+
+#[allow(unused_imports)]
+#[allow(dead_code)]
+#[cfg(feature = "internal")]
+pub mod debug {
+    use libc::{c_void, open, close, mmap, munmap, O_RDONLY, O_CREAT};
+    use libc::{MAP_ANON, MAP_PRIVATE, MAP_FAILED, stat, write, read, fstat};
+    use libc::{PROT_READ, PROT_WRITE, S_IRUSR, S_IWUSR};
+    use libc::{O_WRONLY, mode_t, size_t};
+    use std::ptr;
+    use std::default::Default;
+    use std::ffi::CString;
+    use std::mem;
+
+    use common::{ThreadContext, ReadFileResult};
+    use common::util;
+
+
+    pub fn platform_read_entire_file(context: &ThreadContext,
+                                     filename: &str)
+                                     -> Result<ReadFileResult, ()> {
+
+        let mut result: Result<ReadFileResult, ()> = Err(());
+        let name = CString::new(filename).unwrap();
+        let handle = unsafe { open(name.as_ptr(), O_RDONLY, 0) };
+
+        if handle != -1 {
+            let mut file_stat: stat = unsafe { mem::uninitialized() };
+            if unsafe { fstat(handle, &mut file_stat) != -1 } {
+                let size = util::safe_truncate_u64(file_stat.st_size as u64);
+                let memory: *mut c_void = unsafe {
+                    mmap(ptr::null_mut(),
+                         size as size_t,
+                         PROT_WRITE,
+                         MAP_PRIVATE | MAP_ANON,
+                         -1,
+                         0)
+                };
+
+                if !memory.is_null() {
+                    let mut bytes_to_read = size;
+                    let mut next_write_byte: *mut u8 = memory as *mut u8;
+
+                    while bytes_to_read > 0 {
+                        let bytes_read = unsafe {
+                            read(handle,
+                                 next_write_byte as *mut c_void,
+                                 bytes_to_read as usize)
+                        };
+                        if bytes_read == -1 {
+                            break;
+                        }
+                        bytes_to_read -= bytes_read as u32;
+                        next_write_byte = unsafe { next_write_byte.offset(bytes_read as isize) };
+                    }
+
+                    if bytes_to_read == 0 {
+                        result = Ok(ReadFileResult {
+                            size: size,
+                            contents: memory as *mut u8,
+                        });
+                    } else {
+                        println!("Reading the file contents failed! ({})", filename);
+                        platform_free_file_memory(context, memory as *mut u8, size);
+                    }
+                } else {
+                    println!("Not enough memory could be optained!");
+                }
+            } else {
+                println!("Fstat for the File was not successfull! ({})", filename);
+            }
+            unsafe {
+                close(handle);
+            }
+        } else {
+            println!("The File could not be opened! ({})", filename);
+        }
+
+        result
+    }
+
+    pub fn platform_free_file_memory(_context: &ThreadContext, memory: *mut u8, size: u32) {
+        if !memory.is_null() {
+            unsafe {
+                munmap(memory as *mut c_void, size as usize);
+            }
+        }
+    }
+
+    pub fn platform_write_entire_file(_context: &ThreadContext,
+                                      filename: &str,
+                                      size: u32,
+                                      memory: *mut u8)
+                                      -> bool {
+        let mut result = false;
+        let name = CString::new(filename).unwrap();
+        let handle = unsafe {
+            open(name.as_ptr(),
+                 O_WRONLY | O_CREAT,
+                 S_IRUSR | S_IWUSR | super::S_IRGRP | super::S_IROTH)
+        };
+
+        if handle != -1 {
+            let mut bytes_to_write = size;
+            let mut byte_to_write: *mut u8 = memory;
+
+            while bytes_to_write > 0 {
+                let bytes_written = unsafe {
+                    write(handle,
+                          byte_to_write as *const c_void,
+                          bytes_to_write as usize)
+                };
+                if bytes_written == -1 {
+                    break;
+                }
+                bytes_to_write -= bytes_written as u32;
+                byte_to_write = unsafe { byte_to_write.offset(bytes_written as isize) };
+            }
+
+            if bytes_to_write == 0 {
+                result = true;
+            }
+
+            unsafe {
+                close(handle);
+            }
+        }
+        result
+    }
+
+}
+
+// Streaming access to files and listing them, unlike the debug functions
+// above nothing is read before the game asks for it
+pub mod file {
+    use libc::{c_int, c_void, off_t, open, close, pread, fstat, stat, O_RDONLY};
+    use libc::{opendir, readdir, closedir, dirfd, dirent, fstatat, DIR, DT_DIR, DT_UNKNOWN};
+    use libc::{S_IFMT, S_IFDIR};
+    use std::ffi::{CStr, CString};
+    use std::mem;
+
+    use common::{ThreadContext, PlatformFile, LogLevel};
+    use common::util;
+    use logging::platform_log;
+
+    macro_rules! log_error {
+        ( $context:expr, $($arg:tt)+ ) => {{
+            platform_log($context, LogLevel::Error, file!(), line!(), format_args!($($arg)+))
+        }}
+    }
+
+    pub fn platform_open_file(context: &ThreadContext,
+                              filename: &str)
+                              -> Result<PlatformFile, ()> {
+        let name = match CString::new(filename) {
+            Ok(name) => name,
+            Err(_) => {
+                log_error!(context, "The file name contains a NUL byte! ({:?})", filename);
+                return Err(());
+            }
+        };
+        let handle = unsafe { open(name.as_ptr(), O_RDONLY, 0) };
+        if handle == -1 {
+            log_error!(context, "The File could not be opened! ({})", filename);
+            return Err(());
+        }
+
+        let mut file_stat: stat = unsafe { mem::uninitialized() };
+        if unsafe { fstat(handle, &mut file_stat) } == -1 {
+            log_error!(context, "Fstat for the File was not successfull! ({})", filename);
+            unsafe {
+                close(handle);
+            }
+            return Err(());
+        }
+
+        Ok(PlatformFile {
+            size: file_stat.st_size as u64,
+            handle: handle as usize,
+        })
+    }
+
+    // pread doesn't move a shared file position, so work queue threads can
+    // read from the same file at once
+    pub fn platform_read_file(context: &ThreadContext,
+                              file: &PlatformFile,
+                              offset: u64,
+                              dest: &mut [u8])
+                              -> Result<(), ()> {
+        match offset.checked_add(dest.len() as u64) {
+            Some(end) if end <= file.size => {}
+            _ => return Err(()),
+        }
+
+        let mut bytes_done = 0;
+        while bytes_done < dest.len() {
+            let bytes_read = unsafe {
+                pread(file.handle as c_int,
+                      dest[bytes_done..].as_mut_ptr() as *mut c_void,
+                      dest.len() - bytes_done,
+                      (offset + bytes_done as u64) as off_t)
+            };
+            // 0 means the file got shorter since it was opened
+            if bytes_read <= 0 {
+                log_error!(context, "Reading from the File failed!");
+                return Err(());
+            }
+            bytes_done += bytes_read as usize;
+        }
+        Ok(())
+    }
+
+    pub fn platform_close_file(_context: &ThreadContext, file: PlatformFile) {
+        unsafe {
+            close(file.handle as c_int);
+        }
+    }
+
+    // Not every file system fills in d_type, for those stat has to tell
+    fn is_dir(handle: *mut DIR, entry: &dirent) -> bool {
+        if entry.d_type != DT_UNKNOWN {
+            return entry.d_type == DT_DIR;
+        }
+        let mut file_stat: stat = unsafe { mem::uninitialized() };
+        let result = unsafe { fstatat(dirfd(handle), entry.d_name.as_ptr(), &mut file_stat, 0) };
+        result == 0 && (file_stat.st_mode & S_IFMT) == S_IFDIR
+    }
+
+    pub fn platform_list_files(context: &ThreadContext,
+                               dir: &str,
+                               extension: &str,
+                               dest: &mut [u8])
+                               -> Result<usize, ()> {
+        let mut paths = Vec::new();
+        let name = match CString::new(dir) {
+            Ok(name) => name,
+            Err(_) => {
+                log_error!(context, "The directory name contains a NUL byte! ({:?})", dir);
+                return Err(());
+            }
+        };
+        let handle = unsafe { opendir(name.as_ptr()) };
+        if handle.is_null() {
+            log_error!(context, "The directory could not be opened! ({})", dir);
+            return Err(());
+        }
+
+        let suffix = format!(".{}", extension);
+        loop {
+            let entry = unsafe { readdir(handle) };
+            if entry.is_null() {
+                break;
+            }
+            let entry = unsafe { &*entry };
+            let file_name = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) };
+            if let Ok(file_name) = file_name.to_str() {
+                if file_name.ends_with(&suffix) && !is_dir(handle, entry) {
+                    paths.push(format!("{}/{}", dir, file_name));
+                }
+            }
+        }
+        unsafe {
+            closedir(handle);
+        }
+
+        paths.sort();
+        Ok(util::pack_file_list(&paths, dest))
+    }
+}
+
+pub struct Game {
+    pub handle: *mut c_void,
+    pub get_sound_samples: GetSoundSamplesT,
+    pub update_and_render: UpdateAndRenderT,
+    pub abi: GameAbi,
+    pub temp_so_name: Option<CString>,
+}
+
+// Stub functons if none of the game Code could be loaded!
+pub extern "C" fn get_sound_samples_stub(_: &ThreadContext,
+                                         _: &mut GameMemory,
+                                         _: &mut SoundBuffer) {
+}
+pub extern "C" fn update_and_render_stub(_: &ThreadContext,
+                                         _: &mut GameMemory,
+                                         _: &Input,
+                                         _: &mut VideoBuffer) {
+}
+
+
+fn copy_file(from: &CString, to: &CString) -> bool {
+    let mut result = false;
+
+    let in_fd = unsafe { open(from.as_ptr(), O_RDONLY, 0) };
+    if in_fd != -1 {
+        let out_fd = unsafe {
+            open(to.as_ptr(),
+                 O_WRONLY | O_CREAT | O_TRUNC,
+                 S_IRUSR | S_IWUSR | S_IXUSR | S_IRGRP | S_IROTH)
+        };
+
+        if out_fd != -1 {
+            let mut file_stat: stat = unsafe { mem::uninitialized() };
+            if unsafe { fstat(in_fd, &mut file_stat) != -1 } {
+                let mut bytes_to_copy = file_stat.st_size as usize;
+                while bytes_to_copy > 0 {
+                    let copied = unsafe {
+                        linux::sendfile(out_fd, in_fd, ptr::null_mut(), bytes_to_copy)
+                    };
+                    if copied <= 0 {
+                        break;
+                    }
+                    bytes_to_copy -= copied as usize;
+                }
+                result = bytes_to_copy == 0;
+            }
+            unsafe {
+                close(out_fd);
+            }
+        }
+        unsafe {
+            close(in_fd);
+        }
+    }
+
+    result
+}
+
+fn load_symbol(handle: *mut c_void, name: &str) -> *mut c_void {
+    let name = CString::new(name).unwrap();
+    unsafe { linux::dlsym(handle, name.as_ptr()) }
+}
+
+// Every load copies the library to a fresh temp name, the currently loaded
+// copy must never be overwritten while it is still mapped and dlopen would
+// hand back the old handle for a path it has already opened.
+pub fn load_game_functions(game_so_name: &CString, temp_so_name: &CString) -> Result<Game, ()> {
+    if !copy_file(game_so_name, temp_so_name) {
+        println!("The game library could not be copied! ({:?})", game_so_name);
+        unsafe {
+            unlink(temp_so_name.as_ptr());
+        }
+        return Err(());
+    }
+
+    let handle = unsafe { linux::dlopen(temp_so_name.as_ptr(), linux::RTLD_LAZY) };
+    if handle.is_null() {
+        println!("{:?}", linux::dl_error());
+        unsafe {
+            unlink(temp_so_name.as_ptr());
+        }
+        return Err(());
+    }
+
+    let get_sound_samples = load_symbol(handle, "get_sound_samples");
+    let update_and_render = load_symbol(handle, "update_and_render");
+    let get_game_abi = load_symbol(handle, "get_game_abi");
+
+    if get_sound_samples.is_null() || update_and_render.is_null() || get_game_abi.is_null() {
+        println!("The game library is missing some of its entry points!");
+        unsafe {
+            linux::dlclose(handle);
+            unlink(temp_so_name.as_ptr());
+        }
+        return Err(());
+    }
+
+    let get_game_abi: GetGameAbiT = unsafe { mem::transmute(get_game_abi) };
+    let abi = get_game_abi();
+    if abi.version != GAME_ABI_VERSION {
+        println!("The game library was built against platform ABI {} but this executable uses \
+                  {}!",
+                 abi.version,
+                 GAME_ABI_VERSION);
+        unsafe {
+            linux::dlclose(handle);
+            unlink(temp_so_name.as_ptr());
+        }
+        return Err(());
+    }
+
+    Ok(Game {
+        handle: handle,
+        get_sound_samples: unsafe { mem::transmute(get_sound_samples) },
+        update_and_render: unsafe { mem::transmute(update_and_render) },
+        abi: abi,
+        temp_so_name: Some(temp_so_name.clone()),
+    })
+}
+
+// Every load needs a new temp name, see load_game_functions
+pub fn temp_so_name(temp_dir: &Path, load_count: u32) -> CString {
+    let mut temp_so_path = temp_dir.to_path_buf();
+    temp_so_path.push(format!("libgame_temp_{}.so", load_count));
+    CString::new(temp_so_path.to_str().unwrap()).unwrap()
+}
+
+pub fn unload_game_functions(game: &mut Game) {
+    if !game.handle.is_null() {
+        unsafe {
+            linux::dlclose(game.handle);
+        }
+        game.handle = ptr::null_mut();
+    }
+    if let Some(temp_so_name) = game.temp_so_name.take() {
+        unsafe {
+            unlink(temp_so_name.as_ptr());
+        }
+    }
+    game.get_sound_samples = get_sound_samples_stub;
+    game.update_and_render = update_and_render_stub;
+}
+
+// Callbacks on the queues point into the game library, it may only be
+// unloaded once nothing is queued anymore
+pub fn complete_all_game_work(context: &ThreadContext, game_memory: &GameMemory) {
+    (game_memory.platform_complete_all_work)(context, game_memory.high_priority_queue);
+    (game_memory.platform_complete_all_work)(context, game_memory.low_priority_queue);
+}
+
+pub fn get_exe_path() -> PathBuf {
+    read_link("/proc/self/exe").unwrap()
+}
+
+// Returns the game library and the directory its temporary copies go to
+pub fn get_game_so_names() -> (CString, PathBuf) {
+    let mut game_so_path = get_exe_path();
+    game_so_path.pop();
+    game_so_path.push("deps/libgame.so");
+
+    game_so_names_for(&game_so_path)
+}
+
+pub fn game_so_names_for(game_so_path: &Path) -> (CString, PathBuf) {
+    let mut temp_dir = game_so_path.to_path_buf();
+    temp_dir.pop();
+
+    (CString::new(game_so_path.to_str().unwrap()).unwrap(), temp_dir)
+}
+
+// Where the game memory goes in internal builds. Snapshots, input recordings
+// and crash dumps hold raw pointers into it, they only work when the memory
+// ends up at the same address in every session.
+const GAME_MEMORY_BASE: usize = 2 << 40;
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+// Not in every libc version, kernels before 4.17 take it as a plain hint
+const MAP_FIXED_NOREPLACE: c_int = 0x100000;
+
+pub struct GameMemoryBlock {
+    pub permanent: *mut u8,
+    pub transient: *mut u8,
+}
+
+fn round_up(size: usize, alignment: usize) -> usize {
+    (size + alignment - 1) / alignment * alignment
+}
+
+// Reserves guard | permanent | guard | transient | guard in one go, the
+// guard pages stay inaccessible so running off the end of either store
+// faults right away instead of corrupting the other one.
+pub fn allocate_game_memory(permanent_store_size: usize,
+                            transient_store_size: usize,
+                            huge_pages: bool)
+                            -> Result<GameMemoryBlock, ()> {
+    let page_size = if huge_pages {
+        HUGE_PAGE_SIZE
+    } else {
+        unsafe { sysconf(_SC_PAGESIZE) as usize }
+    };
+    let permanent_size = round_up(permanent_store_size, page_size);
+    let transient_size = round_up(transient_store_size, page_size);
+    let total_size = page_size + permanent_size + page_size + transient_size + page_size;
+
+    let (base_address, flags) = if cfg!(feature = "internal") {
+        (GAME_MEMORY_BASE, MAP_PRIVATE | MAP_ANON | MAP_NORESERVE | MAP_FIXED_NOREPLACE)
+    } else {
+        (0, MAP_PRIVATE | MAP_ANON | MAP_NORESERVE)
+    };
+
+    let memory = unsafe {
+        mmap(base_address as *mut c_void, total_size, PROT_NONE, flags, -1, 0)
+    };
+    if memory == MAP_FAILED {
+        println!("The game memory could not be reserved! ({} bytes at {:#x}: {})",
+                 total_size,
+                 base_address,
+                 io::Error::last_os_error());
+        return Err(());
+    }
+    if base_address != 0 && memory as usize != base_address {
+        println!("The game memory could not be reserved at {:#x}, the system put it at {:?}!",
+                 base_address,
+                 memory);
+        unsafe {
+            munmap(memory, total_size);
+        }
+        return Err(());
+    }
+
+    let permanent = unsafe { (memory as *mut u8).offset(page_size as isize) };
+    let transient = unsafe { permanent.offset((permanent_size + page_size) as isize) };
+    for &(store, size) in &[(permanent, permanent_size), (transient, transient_size)] {
+        if unsafe { mprotect(store as *mut c_void, size, PROT_READ | PROT_WRITE) } != 0 {
+            println!("The game memory could not be made accessible! ({})",
+                     io::Error::last_os_error());
+            unsafe {
+                munmap(memory, total_size);
+            }
+            return Err(());
+        }
+
+        // Transparent huge pages, the kernel backs what it can with 2MB pages
+        if huge_pages && unsafe { madvise(store as *mut c_void, size, MADV_HUGEPAGE) } != 0 {
+            println!("Huge pages are not available, using normal pages! ({})",
+                     io::Error::last_os_error());
+        }
+    }
+
+    Ok(GameMemoryBlock {
+        permanent: permanent,
+        transient: transient,
+    })
+}
+
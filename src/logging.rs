@@ -0,0 +1,140 @@
+// Platform log
+//
+// Backs GameMemory::platform_log and is used by the platform layer itself.
+// Every message goes to stderr and, once init() was called with a path, to
+// a log file. When the file grows past MAX_LOG_SIZE it is rotated to
+// name.1, name.1 to name.2 and so on, the oldest of LOG_FILES_KEPT is
+// dropped.
+//
+// The game calls in through a plain function pointer, so the state lives in
+// a global behind a mutex, the work queue threads may log as well.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use common::{LogLevel, ThreadContext};
+
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+const LOG_FILES_KEPT: u32 = 3;
+
+struct Logger {
+    path: Option<PathBuf>,
+    file: Option<File>,
+    size: u64,
+}
+
+static INIT: Once = ONCE_INIT;
+static mut LOGGER: *const Mutex<Logger> = 0 as *const Mutex<Logger>;
+static FRAME: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn logger() -> &'static Mutex<Logger> {
+    INIT.call_once(|| {
+        let logger = Box::new(Mutex::new(Logger {
+            path: None,
+            file: None,
+            size: 0,
+        }));
+        // Never freed, the log is needed until the very end
+        unsafe {
+            LOGGER = Box::into_raw(logger);
+        }
+    });
+    unsafe { &*LOGGER }
+}
+
+fn rotated_path(path: &PathBuf, index: u32) -> PathBuf {
+    let mut name = path.clone().into_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+impl Logger {
+    fn open(&mut self) {
+        self.file = None;
+        self.size = 0;
+        if let Some(ref path) = self.path {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => {
+                    self.size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+                    self.file = Some(file);
+                }
+                Err(error) => {
+                    let _ = writeln!(io::stderr(),
+                                     "The log file could not be opened! ({:?}: {})",
+                                     path,
+                                     error);
+                }
+            }
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.file = None;
+        if let Some(ref path) = self.path {
+            for index in (1..LOG_FILES_KEPT).rev() {
+                let _ = fs::rename(rotated_path(path, index), rotated_path(path, index + 1));
+            }
+            let _ = fs::rename(path, rotated_path(path, 1));
+        }
+        self.open();
+    }
+
+    fn write_line(&mut self, line: &str) {
+        let _ = io::stderr().write_all(line.as_bytes());
+
+        if self.size + line.len() as u64 > MAX_LOG_SIZE {
+            self.rotate();
+        }
+        let mut failed = false;
+        if let Some(ref mut file) = self.file {
+            failed = file.write_all(line.as_bytes()).is_err();
+        }
+        if failed {
+            self.file = None;
+        } else {
+            self.size += line.len() as u64;
+        }
+    }
+}
+
+// Starts writing to the log file at path as well, appending to what an
+// earlier run left there. The headless runner only logs to stderr.
+#[allow(dead_code)]
+pub fn init(path: PathBuf) {
+    let mut logger = logger().lock().unwrap();
+    logger.path = Some(path);
+    logger.open();
+}
+
+// Every message is tagged with the frame it was logged in
+pub fn set_frame(frame: u64) {
+    FRAME.store(frame as usize, Ordering::Relaxed);
+}
+
+pub fn log(level: LogLevel, file: &str, line: u32, message: fmt::Arguments) {
+    let text = format!("[frame {}] {} {}:{}: {}\n",
+                       FRAME.load(Ordering::Relaxed),
+                       level.name(),
+                       file,
+                       line,
+                       message);
+
+    // A panic while logging must not take the log down with it
+    let mut logger = match logger().lock() {
+        Ok(logger) => logger,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    logger.write_line(&text);
+}
+
+pub fn platform_log(_context: &ThreadContext,
+                    level: LogLevel,
+                    file: &str,
+                    line: u32,
+                    message: fmt::Arguments) {
+    log(level, file, line, message);
+}
@@ -7,6 +7,8 @@ extern crate libc;
 
 mod common;
 
+mod work_queue;
+
 #[cfg(target_os="windows")]
 mod ffi;
 #[cfg(target_os="windows")]
@@ -26,6 +28,30 @@ mod ffi {
 #[cfg(target_os="linux")]
 mod linux;
 
+#[cfg(target_os="linux")]
+mod linux_shared;
+
+#[cfg(target_os="linux")]
+mod bindings;
+
+#[cfg(target_os="linux")]
+mod framedump;
+
+#[cfg(target_os="linux")]
+mod wav;
+
+#[cfg(target_os="linux")]
+mod options;
+
+#[cfg(target_os="linux")]
+mod audio;
+
+#[cfg(target_os="linux")]
+mod logging;
+
+#[cfg(target_os="linux")]
+mod crash;
+
 #[cfg(target_os="linux")]
 fn main() {
     linux::linuxmain();
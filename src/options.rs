@@ -0,0 +1,267 @@
+// Command line options of the SDL platform layer
+//
+// Everything defaults to what used to be hard-coded, so running without any
+// arguments behaves exactly as before. Paths are made absolute while parsing
+// because the platform changes into the asset directory before the game
+// starts.
+
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use common::util;
+
+const DEFAULT_WINDOW_WIDTH: i32 = 960;
+const DEFAULT_WINDOW_HEIGHT: i32 = 540;
+const DEFAULT_PERMANENT_MB: usize = 64;
+const DEFAULT_TRANSIENT_MB: usize = 1024;
+const DEFAULT_AUDIO_LATENCY_MS: u32 = 1000 / 15;
+
+const MAX_WINDOW_SIZE: i32 = 16384;
+const MAX_FRAME_RATE: u32 = 1000;
+// The SDL ring buffer holds one second of audio
+const MAX_AUDIO_LATENCY_MS: u32 = 999;
+
+// How the backbuffer is fitted into a window of a different size
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Scaling {
+    // The backbuffer follows the window size, the game renders at the new
+    // resolution
+    Resize,
+    // Fixed game resolution, scaled as large as the window allows while
+    // keeping the aspect ratio, the rest is letterboxed
+    Aspect,
+    // Like Aspect but only whole multiples of the game resolution
+    Integer,
+}
+
+impl Scaling {
+    fn from_name(name: &str) -> Option<Scaling> {
+        match name {
+            "resize" => Some(Scaling::Resize),
+            "aspect" => Some(Scaling::Aspect),
+            "integer" => Some(Scaling::Integer),
+            _ => None,
+        }
+    }
+}
+
+// Where the audio goes. If the SDL device can't be opened the platform falls
+// back to Null.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum AudioBackend {
+    Sdl,
+    // Consumes the samples at real-time rate without playing them
+    Null,
+    // Like Null but writes the samples into the --audio-file WAV
+    File,
+}
+
+impl AudioBackend {
+    fn from_name(name: &str) -> Option<AudioBackend> {
+        match name {
+            "sdl" => Some(AudioBackend::Sdl),
+            "null" => Some(AudioBackend::Null),
+            "file" => Some(AudioBackend::File),
+            _ => None,
+        }
+    }
+}
+
+pub struct Options {
+    pub window_width: i32,
+    pub window_height: i32,
+    pub fullscreen: bool,
+    pub scaling: Scaling,
+    pub game_library: Option<PathBuf>,
+    pub asset_dir: Option<PathBuf>,
+    pub permanent_size: usize,
+    pub transient_size: usize,
+    pub huge_pages: bool,
+    // None runs the game at half the monitor refresh rate
+    pub frame_rate: Option<u32>,
+    pub audio_latency_ms: u32,
+    pub audio_backend: AudioBackend,
+    pub audio_file: Option<PathBuf>,
+    pub dump_dir: Option<PathBuf>,
+    pub dump_playback: bool,
+    pub capture_audio: Option<PathBuf>,
+    pub crash_dir: Option<PathBuf>,
+    pub resume_crash: Option<PathBuf>,
+    pub replay_crash: Option<PathBuf>,
+}
+
+pub enum ParseError {
+    Help,
+    Invalid(String),
+}
+
+pub const USAGE: &'static str = "\
+Usage: rust_hero [options]
+    --width N              window width in pixels (default 960)
+    --height N             window height in pixels (default 540)
+    --fullscreen           start in borderless fullscreen (toggle with Alt+Enter)
+    --scaling MODE         resize, aspect or integer (default aspect)
+    --game-library FILE    game library to load (default deps/libgame.so next to \
+the executable)
+    --asset-dir DIR        directory the game loads its assets from (default: \
+the working directory)
+    --permanent-mb N       size of the permanent game memory (default 64)
+    --transient-mb N       size of the transient game memory (default 1024)
+    --huge-pages           back the game memory with huge pages where possible
+    --fps N                game update rate (default: half the monitor refresh rate)
+    --audio-latency-ms N   how far ahead of the play cursor audio is written \
+(default 66)
+    --audio BACKEND        sdl, null or file (default sdl, null if no device is found)
+    --audio-file FILE      WAV file the file backend writes to
+    --dump-dir DIR         directory for frame dumps (default: frames next to the \
+executable)
+    --dump-playback        play input.rhi back once, dump every frame and quit
+    --capture-audio FILE   write the game's audio output to a WAV file
+    --crash-dir DIR        directory for crash dumps (default: crashes next to the \
+executable)
+    --resume-crash DIR     continue the game from the memory of a crash dump
+    --replay-crash DIR     play the input of a crash dump back from its snapshot
+    --help                 show this message";
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            window_width: DEFAULT_WINDOW_WIDTH,
+            window_height: DEFAULT_WINDOW_HEIGHT,
+            fullscreen: false,
+            scaling: Scaling::Aspect,
+            game_library: None,
+            asset_dir: None,
+            permanent_size: util::mega_bytes(DEFAULT_PERMANENT_MB),
+            transient_size: util::mega_bytes(DEFAULT_TRANSIENT_MB),
+            huge_pages: false,
+            frame_rate: None,
+            audio_latency_ms: DEFAULT_AUDIO_LATENCY_MS,
+            audio_backend: AudioBackend::Sdl,
+            audio_file: None,
+            dump_dir: None,
+            dump_playback: false,
+            capture_audio: None,
+            crash_dir: None,
+            resume_crash: None,
+            replay_crash: None,
+        }
+    }
+}
+
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, ParseError> {
+    let mut options = Options::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--help" | "-h" => return Err(ParseError::Help),
+            "--width" => {
+                options.window_width = parse_number(&arg, args.next(), 1, MAX_WINDOW_SIZE)?
+            }
+            "--height" => {
+                options.window_height = parse_number(&arg, args.next(), 1, MAX_WINDOW_SIZE)?
+            }
+            "--fullscreen" => options.fullscreen = true,
+            "--scaling" => {
+                let name = args.next().unwrap_or_default();
+                options.scaling = Scaling::from_name(&name).ok_or_else(|| {
+                    ParseError::Invalid(format!("{} expects resize, aspect or integer", arg))
+                })?;
+            }
+            "--game-library" => options.game_library = Some(parse_path(&arg, args.next())?),
+            "--asset-dir" => {
+                let dir = parse_path(&arg, args.next())?;
+                if !dir.is_dir() {
+                    return Err(ParseError::Invalid(format!("{}: {:?} is not a directory",
+                                                           arg,
+                                                           dir)));
+                }
+                options.asset_dir = Some(dir);
+            }
+            "--permanent-mb" => {
+                let size: usize = parse_number(&arg, args.next(), 1, usize::max_value())?;
+                options.permanent_size = mega_bytes_checked(&arg, size)?;
+            }
+            "--transient-mb" => {
+                let size: usize = parse_number(&arg, args.next(), 1, usize::max_value())?;
+                options.transient_size = mega_bytes_checked(&arg, size)?;
+            }
+            "--huge-pages" => options.huge_pages = true,
+            "--fps" => {
+                options.frame_rate = Some(parse_number(&arg, args.next(), 1, MAX_FRAME_RATE)?)
+            }
+            "--audio-latency-ms" => {
+                options.audio_latency_ms =
+                    parse_number(&arg, args.next(), 1, MAX_AUDIO_LATENCY_MS)?
+            }
+            "--audio" => {
+                let name = args.next().unwrap_or_default();
+                options.audio_backend = AudioBackend::from_name(&name).ok_or_else(|| {
+                    ParseError::Invalid(format!("{} expects sdl, null or file", arg))
+                })?;
+            }
+            "--audio-file" => options.audio_file = Some(parse_path(&arg, args.next())?),
+            "--dump-dir" => options.dump_dir = Some(parse_path(&arg, args.next())?),
+            "--dump-playback" => options.dump_playback = true,
+            "--capture-audio" => options.capture_audio = Some(parse_path(&arg, args.next())?),
+            "--crash-dir" => options.crash_dir = Some(parse_path(&arg, args.next())?),
+            "--resume-crash" => options.resume_crash = Some(parse_path(&arg, args.next())?),
+            "--replay-crash" => options.replay_crash = Some(parse_path(&arg, args.next())?),
+            _ => return Err(ParseError::Invalid(format!("Unknown argument: {}", arg))),
+        }
+    }
+
+    if options.audio_backend == AudioBackend::File && options.audio_file.is_none() {
+        return Err(ParseError::Invalid("--audio file needs an --audio-file".to_string()));
+    }
+
+    if options.resume_crash.is_some() && options.replay_crash.is_some() {
+        return Err(ParseError::Invalid("--resume-crash and --replay-crash can't be combined"
+                                           .to_string()));
+    }
+
+    if options.permanent_size.checked_add(options.transient_size).is_none() {
+        return Err(ParseError::Invalid("The game memory does not fit into the address space"
+                                           .to_string()));
+    }
+
+    Ok(options)
+}
+
+fn parse_number<T>(arg: &str, value: Option<String>, min: T, max: T) -> Result<T, ParseError>
+    where T: FromStr + PartialOrd + ToString
+{
+    let value = value.ok_or_else(|| ParseError::Invalid(format!("{} expects a number", arg)))?;
+    match value.parse::<T>() {
+        Ok(number) => {
+            if number >= min && number <= max {
+                Ok(number)
+            } else {
+                Err(ParseError::Invalid(format!("{} has to be between {} and {}",
+                                                arg,
+                                                min.to_string(),
+                                                max.to_string())))
+            }
+        }
+        Err(_) => Err(ParseError::Invalid(format!("{} expects a number, got {}", arg, value))),
+    }
+}
+
+fn parse_path(arg: &str, value: Option<String>) -> Result<PathBuf, ParseError> {
+    let value = value.ok_or_else(|| ParseError::Invalid(format!("{} expects a path", arg)))?;
+    let path = PathBuf::from(value);
+    if path.is_absolute() {
+        Ok(path)
+    } else {
+        match env::current_dir() {
+            Ok(dir) => Ok(dir.join(path)),
+            Err(_) => Ok(path),
+        }
+    }
+}
+
+fn mega_bytes_checked(arg: &str, size: usize) -> Result<usize, ParseError> {
+    size.checked_mul(1024 * 1024)
+        .ok_or_else(|| ParseError::Invalid(format!("{} is too large", arg)))
+}
@@ -0,0 +1,90 @@
+// WAV capture
+//
+// Tees the samples the game produced into a 16 bit stereo PCM WAV file so
+// the audio output can be inspected offline or compared between runs. The
+// RIFF and data chunk sizes are only known at the end, finish() patches them
+// in, until then the file claims to hold no samples.
+//
+// The sizes are 32 bit, which is good for about 6.7 hours at 48kHz. Samples
+// past MAX_DATA_SIZE are dropped with a warning, the file stays valid.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::u32;
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_SIZE: u32 = 44;
+const RIFF_SIZE_OFFSET: u64 = 4;
+const DATA_SIZE_OFFSET: u64 = 40;
+const MAX_DATA_SIZE: u32 = u32::MAX - (HEADER_SIZE - 8);
+
+pub type WavFile = WavWriter<BufWriter<File>>;
+
+pub fn create(path: &Path, samples_per_second: u32) -> io::Result<WavFile> {
+    File::create(path).and_then(|file| WavWriter::new(BufWriter::new(file), samples_per_second))
+}
+
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, samples_per_second: u32) -> io::Result<WavWriter<W>> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+
+        out.write_all(b"RIFF")?;
+        out.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        out.write_all(b"WAVE")?;
+
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&CHANNELS.to_le_bytes())?;
+        out.write_all(&samples_per_second.to_le_bytes())?;
+        out.write_all(&(samples_per_second * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            out: out,
+            data_size: 0,
+        })
+    }
+
+    // samples are interleaved left/right like in the SoundBuffer
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let bytes_left = (MAX_DATA_SIZE - self.data_size) as usize;
+        let mut samples = samples;
+        if samples.len() * 2 > bytes_left {
+            if bytes_left >= 2 * CHANNELS as usize {
+                println!("The WAV file is full, dropping all further samples!");
+            }
+            // Whole frames only, the channels must not get swapped
+            let frame_bytes = 2 * CHANNELS as usize;
+            samples = &samples[..bytes_left / frame_bytes * CHANNELS as usize];
+        }
+
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size += (samples.len() * 2) as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.out.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        self.out.write_all(&self.data_size.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
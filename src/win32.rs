@@ -5,10 +5,16 @@ use std::mem;
 use std::i16;
 use std::ffi::CString;
 use std::iter::FromIterator;
+use std::fs::File;
+use std::fmt;
 
 use common::util;
+use common::recording::{RecordingReader, RecordingWriter};
 use common::{Input, GameMemory, SoundBuffer, ControllerInput, Button, VideoBuffer};
 use common::{ThreadContext, GetSoundSamplesT, UpdateAndRenderT};
+use common::timing::{FrameStats, FrameTiming};
+use common::LogLevel;
+use work_queue;
 use ffi::*;
 
 #[cfg(feature = "internal")]
@@ -152,9 +158,166 @@ pub mod debug {
     }
 }
 
+// Streaming access to files and listing them, unlike the debug functions
+// above nothing is read before the game asks for it
+pub mod file {
+    use std::cmp;
+    use std::ffi::{CStr, CString};
+    use std::mem;
+    use std::ptr;
+    use std::u32;
+
+    use ffi::*;
+    use common::{ThreadContext, PlatformFile, LogLevel};
+    use common::util;
+    use super::platform_log;
+
+    macro_rules! log_error {
+        ( $context:expr, $($arg:tt)+ ) => {{
+            platform_log($context, LogLevel::Error, file!(), line!(), format_args!($($arg)+))
+        }}
+    }
+
+    pub fn platform_open_file(context: &ThreadContext,
+                              filename: &str)
+                              -> Result<PlatformFile, ()> {
+        debug_assert!(filename.len() <= MAX_PATH);
+
+        let name = match CString::new(filename) {
+            Ok(name) => name,
+            Err(_) => {
+                log_error!(context, "The file name contains a NUL byte! ({:?})", filename);
+                return Err(());
+            }
+        };
+        let handle = unsafe {
+            CreateFileA(name.as_ptr(),
+                        GENERIC_READ,
+                        FILE_SHARE_READ,
+                        ptr::null_mut(),
+                        OPEN_EXISTING,
+                        FILE_ATTRIBUTE_NORMAL,
+                        ptr::null_mut())
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            log_error!(context, "The File could not be opened! ({})", filename);
+            return Err(());
+        }
+
+        let mut file_size: i64 = 0;
+        if unsafe { GetFileSizeEx(handle, &mut file_size) } == 0 {
+            log_error!(context, "The size of the File could not be read! ({})", filename);
+            unsafe {
+                CloseHandle(handle);
+            }
+            return Err(());
+        }
+
+        Ok(PlatformFile {
+            size: file_size as u64,
+            handle: handle as usize,
+        })
+    }
+
+    // The offset goes in through the OVERLAPPED, so work queue threads can
+    // read from the same file at once
+    pub fn platform_read_file(context: &ThreadContext,
+                              file: &PlatformFile,
+                              offset: u64,
+                              dest: &mut [u8])
+                              -> Result<(), ()> {
+        match offset.checked_add(dest.len() as u64) {
+            Some(end) if end <= file.size => {}
+            _ => return Err(()),
+        }
+
+        let mut bytes_done = 0;
+        while bytes_done < dest.len() {
+            let position = offset + bytes_done as u64;
+            let mut overlapped = OVERLAPPED {
+                Internal: 0,
+                InternalHigh: 0,
+                Offset: position as DWORD,
+                OffsetHigh: (position >> 32) as DWORD,
+                hEvent: ptr::null_mut(),
+            };
+            let bytes_to_read = cmp::min(dest.len() - bytes_done, u32::MAX as usize);
+            let mut bytes_read = 0;
+            let success = unsafe {
+                ReadFile(file.handle as HANDLE,
+                         dest[bytes_done..].as_mut_ptr() as LPVOID,
+                         bytes_to_read as DWORD,
+                         &mut bytes_read,
+                         &mut overlapped)
+            };
+            // 0 bytes means the file got shorter since it was opened
+            if success == 0 || bytes_read == 0 {
+                log_error!(context, "Reading from the File failed!");
+                return Err(());
+            }
+            bytes_done += bytes_read as usize;
+        }
+        Ok(())
+    }
+
+    pub fn platform_close_file(_context: &ThreadContext, file: PlatformFile) {
+        unsafe {
+            CloseHandle(file.handle as HANDLE);
+        }
+    }
+
+    pub fn platform_list_files(context: &ThreadContext,
+                               dir: &str,
+                               extension: &str,
+                               dest: &mut [u8])
+                               -> Result<usize, ()> {
+        let mut paths = Vec::new();
+        let pattern = match CString::new(format!("{}/*.{}", dir, extension)) {
+            Ok(pattern) => pattern,
+            Err(_) => {
+                log_error!(context,
+                           "The directory or extension contains a NUL byte! ({:?}, {:?})",
+                           dir,
+                           extension);
+                return Err(());
+            }
+        };
+        let mut find_data: WIN32_FIND_DATAA = unsafe { mem::zeroed() };
+        let handle = unsafe { FindFirstFileA(pattern.as_ptr(), &mut find_data) };
+        if handle == INVALID_HANDLE_VALUE {
+            // Also what an empty directory looks like
+            return Ok(0);
+        }
+
+        // The pattern matches extensions that merely start with the given
+        // one as well (*.abc finds x.abcd), so check again
+        let suffix = format!(".{}", extension);
+        loop {
+            let file_name = unsafe { CStr::from_ptr(find_data.cFileName.as_ptr()) };
+            if let Ok(file_name) = file_name.to_str() {
+                if (find_data.dwFileAttributes & FILE_ATTRIBUTE_DIRECTORY) == 0 &&
+                   file_name.ends_with(&suffix) {
+                    paths.push(format!("{}/{}", dir, file_name));
+                }
+            }
+            if unsafe { FindNextFileA(handle, &mut find_data) } == 0 {
+                break;
+            }
+        }
+        unsafe {
+            FindClose(handle);
+        }
+
+        paths.sort();
+        Ok(util::pack_file_list(&paths, dest))
+    }
+}
+
 // Graphics System constants
 const BYTES_PER_PIXEL: c_int = 4;
 const DEFAULT_MONITOR_REFRESH_RATE: usize = 60;
+const HIGH_PRIORITY_THREADS: u32 = 3;
+const LOW_PRIORITY_THREADS: u32 = 2;
 
 // Sound System constants
 const CHANNELS: WORD = 2;
@@ -204,11 +367,14 @@ enum ReplayState {
 }
 
 struct Replay {
-    input_path: CString,
-    input_file_handle: HANDLE,
+    input_path: PathBuf,
+    recorder: Option<RecordingWriter<File>>,
+    player: Option<RecordingReader<File>>,
     game_address: *mut c_void,
     memory: *mut c_void,
-    memory_size: usize,
+    permanent_size: usize,
+    transient_size: usize,
+    delta_t: f32,
     state: ReplayState,
 }
 
@@ -222,54 +388,74 @@ impl Replay {
         self.state == ReplayState::Replaying
     }
 
-    fn stop_recording(&mut self) {
+    fn memory_size(&self) -> usize {
+        self.permanent_size + self.transient_size
+    }
+
+    fn restore_snapshot(&mut self) {
         unsafe {
-            CloseHandle(self.input_file_handle);
+            RtlCopyMemory(self.game_address,
+                          self.memory as *const c_void,
+                          self.memory_size() as SIZE_T);
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(error) = recorder.finish() {
+                println!("The input recording could not be finished! ({})", error);
+            }
         }
         self.state = ReplayState::Nothing;
     }
 
     fn start_replay(&mut self) {
-        self.input_file_handle = unsafe {
-            CreateFileA(self.input_path.as_ptr(),
-                        GENERIC_READ,
-                        FILE_SHARE_READ,
-                        ptr::null_mut(),
-                        OPEN_EXISTING,
-                        0,
-                        ptr::null_mut())
-        };
-        unsafe {
-            RtlCopyMemory(self.game_address,
-                          self.memory as *const c_void,
-                          self.memory_size as SIZE_T);
+        let player = File::open(&self.input_path).and_then(RecordingReader::new);
+        match player {
+            Ok(player) => {
+                let header = *player.header();
+                if header.permanent_size != self.permanent_size as u64 ||
+                   header.transient_size != self.transient_size as u64 {
+                    println!("The input recording was made with different memory sizes!");
+                    return;
+                }
+                // Nothing to loop over, the snapshot stays untouched
+                if header.frame_count == 0 {
+                    println!("The input recording is empty!");
+                    return;
+                }
+                self.player = Some(player);
+                self.restore_snapshot();
+                self.state = ReplayState::Replaying;
+            }
+            Err(error) => println!("The input recording could not be read! ({})", error),
         }
-        self.state = ReplayState::Replaying;
     }
 
     fn stop_replay(&mut self) {
-        unsafe {
-            CloseHandle(self.input_file_handle);
-        }
+        self.player = None;
         self.state = ReplayState::Nothing;
     }
 
     fn start_recording(&mut self) {
-        self.input_file_handle = unsafe {
-            CreateFileA(self.input_path.as_ptr(),
-                        GENERIC_WRITE,
-                        0,
-                        ptr::null_mut(),
-                        CREATE_ALWAYS,
-                        0,
-                        ptr::null_mut())
-        };
-        unsafe {
-            RtlCopyMemory(self.memory,
-                          self.game_address as *const c_void,
-                          self.memory_size as SIZE_T);
+        let recorder = File::create(&self.input_path).and_then(|file| {
+            RecordingWriter::new(file,
+                                 self.permanent_size as u64,
+                                 self.transient_size as u64,
+                                 self.delta_t)
+        });
+        match recorder {
+            Ok(recorder) => {
+                self.recorder = Some(recorder);
+                unsafe {
+                    RtlCopyMemory(self.memory,
+                                  self.game_address as *const c_void,
+                                  self.memory_size() as SIZE_T);
+                }
+                self.state = ReplayState::Recording;
+            }
+            Err(error) => println!("The input recording could not be created! ({})", error),
         }
-        self.state = ReplayState::Recording;
     }
 }
 
@@ -1101,6 +1287,14 @@ fn process_mouse_input(window: &Window, input: &mut Input) {
                              unsafe { (GetKeyState(VK_XBUTTON2 as i32) & (1 << 15)) != 0 });
 }
 
+fn platform_log(_context: &ThreadContext,
+                level: LogLevel,
+                file: &str,
+                line: u32,
+                message: fmt::Arguments) {
+    println!("{} {}:{}: {}", level.name(), file, line, message);
+}
+
 fn get_wall_clock() -> i64 {
     let mut res: i64 = 0;
     unsafe {
@@ -1110,7 +1304,8 @@ fn get_wall_clock() -> i64 {
 }
 
 fn get_seconds_elapsed(start: i64, end: i64, frequency: i64) -> f32 {
-    debug_assert!(start < end);
+    // Short spans like the present can end on the same count they started
+    debug_assert!(start <= end);
     (end - start) as f32 / frequency as f32
 }
 
@@ -1129,10 +1324,13 @@ fn get_exe_path() -> PathBuf {
 }
 
 fn initialize_replay(exe_dirname: &PathBuf,
-                     file_size: usize,
+                     permanent_size: usize,
+                     transient_size: usize,
+                     delta_t: f32,
                      game_address: *mut c_void)
                      -> Result<Replay, ()> {
     let mut result: Result<Replay, ()> = Err(());
+    let file_size = permanent_size + transient_size;
 
     let mut mmap_path = exe_dirname.clone();
     mmap_path.push("mmap.rhm");
@@ -1141,7 +1339,6 @@ fn initialize_replay(exe_dirname: &PathBuf,
     input_path.push("input.rhi");
 
     let mmap_name = CString::new(mmap_path.to_str().unwrap()).unwrap();
-    let input_name = CString::new(input_path.to_str().unwrap()).unwrap();
     let file_handle = unsafe {
         CreateFileA(mmap_name.as_ptr(),
                     GENERIC_READ | GENERIC_WRITE,
@@ -1169,11 +1366,14 @@ fn initialize_replay(exe_dirname: &PathBuf,
 
             if !address.is_null() {
                 result = Ok(Replay {
-                    input_path: input_name,
-                    input_file_handle: ptr::null_mut(),
+                    input_path: input_path,
+                    recorder: None,
+                    player: None,
                     game_address: game_address,
                     memory: address,
-                    memory_size: file_size,
+                    permanent_size: permanent_size,
+                    transient_size: transient_size,
+                    delta_t: delta_t,
                     state: ReplayState::Nothing,
                 });
             } else {
@@ -1188,33 +1388,45 @@ fn initialize_replay(exe_dirname: &PathBuf,
     result
 }
 
-fn log_input(replay: &Replay, input: &mut Input) {
-    unsafe {
-        let mut ignored: DWORD = 0;
-        WriteFile(replay.input_file_handle,
-                  input as *mut _ as *mut c_void,
-                  mem::size_of_val(input) as DWORD,
-                  &mut ignored,
-                  ptr::null_mut());
+fn log_input(replay: &mut Replay, input: &Input) {
+    let failed = match replay.recorder.as_mut() {
+        Some(recorder) => recorder.write_input(input).is_err(),
+        None => false,
+    };
+    if failed {
+        println!("Writing the input recording failed, recording stopped!");
+        replay.stop_recording();
     }
 }
 
 fn override_input(replay: &mut Replay, input: &mut Input) {
-    unsafe {
-        if !replay.input_file_handle.is_null() {
-            let mut bytes_read: DWORD = 0;
-            ReadFile(replay.input_file_handle,
-                     input as *mut _ as *mut c_void,
-                     mem::size_of_val(input) as DWORD,
-                     &mut bytes_read,
-                     ptr::null_mut());
-            if bytes_read == 0 {
-                // use recursion here to loop
-                replay.stop_replay();
-                replay.start_replay();
-                override_input(replay, input);
+    let mut looped = false;
+    let read = match replay.player.as_mut() {
+        Some(player) => {
+            match player.read_input(input) {
+                Ok(false) => {
+                    // Restart from the snapshot to loop the recorded section
+                    looped = true;
+                    player.rewind().and_then(|_| player.read_input(input))
+                }
+                result => result,
             }
         }
+        None => return,
+    };
+
+    match read {
+        Ok(true) => {
+            if looped {
+                replay.restore_snapshot();
+            }
+        }
+        // Nothing was recorded at all
+        Ok(false) => replay.stop_replay(),
+        Err(error) => {
+            println!("Reading the input recording failed! ({})", error);
+            replay.stop_replay();
+        }
     }
 }
 
@@ -1372,6 +1584,7 @@ pub fn winmain() {
 
     let mut game_memory: GameMemory = GameMemory {
         initialized: false,
+        restored: false,
         permanent: unsafe { slice::from_raw_parts_mut(memory as *mut u8, permanent_store_size) },
         transient: unsafe {
             slice::from_raw_parts_mut((memory as *mut u8).offset(permanent_store_size as isize),
@@ -1380,9 +1593,25 @@ pub fn winmain() {
         platform_read_entire_file: debug::platform_read_entire_file,
         platform_write_entire_file: debug::platform_write_entire_file,
         platform_free_file_memory: debug::platform_free_file_memory,
+        platform_open_file: file::platform_open_file,
+        platform_read_file: file::platform_read_file,
+        platform_close_file: file::platform_close_file,
+        platform_list_files: file::platform_list_files,
+        platform_log: platform_log,
+        high_priority_queue: work_queue::create_work_queue(HIGH_PRIORITY_THREADS, 1, None),
+        low_priority_queue: work_queue::create_work_queue(LOW_PRIORITY_THREADS,
+                                                          HIGH_PRIORITY_THREADS + 1,
+                                                          None),
+        platform_add_work_entry: work_queue::platform_add_work_entry,
+        platform_complete_all_work: work_queue::platform_complete_all_work,
+        frame_stats: FrameStats::new(target_seconds_per_frame),
     };
 
-    let mut replay = initialize_replay(&exe_dirname, total_size, memory)
+    let mut replay = initialize_replay(&exe_dirname,
+                                       permanent_store_size,
+                                       transient_store_size,
+                                       target_seconds_per_frame,
+                                       memory)
                          .expect("Error with replay");
 
     window.timer_fine_resolution = unsafe { timeBeginPeriod(1) == TIMERR_NOERROR };
@@ -1396,7 +1625,7 @@ pub fn winmain() {
     let game_dll_string = CString::new(game_dll_path.to_str().unwrap()).unwrap();
     let temp_dll_string = CString::new(temp_dll_path.to_str().unwrap()).unwrap();
 
-    let thread_context = ThreadContext;
+    let thread_context = ThreadContext { index: 0 };
 
     let mut sound_is_valid = false;
     let mut last_time_marker_index: usize = 0;
@@ -1427,7 +1656,11 @@ pub fn winmain() {
             dwHighDateTime: 0,
         });
         if unsafe { CompareFileTime(&game.write_time, &new_write_time) } != 0 {
-
+            // The queued callbacks live in the old dll
+            (game_memory.platform_complete_all_work)(&thread_context,
+                                                     game_memory.high_priority_queue);
+            (game_memory.platform_complete_all_work)(&thread_context,
+                                                     game_memory.low_priority_queue);
             unload_game_functions(&mut game);
             game = load_game_functions(&game_dll_string, &temp_dll_string);
         }
@@ -1456,14 +1689,22 @@ pub fn winmain() {
             };
 
             if replay.is_recording() {
-                log_input(&replay, new_input);
+                log_input(&mut replay, new_input);
             }
 
             if replay.is_replaying() {
                 override_input(&mut replay, new_input);
             }
 
+            let mut timing: FrameTiming = Default::default();
+
+            let update_counter = get_wall_clock();
             (game.update_and_render)(&thread_context, &mut game_memory, new_input, &mut video_buf);
+            timing.update = get_seconds_elapsed(update_counter,
+                                                get_wall_clock(),
+                                                counter_frequency);
+
+            let audio_counter = get_wall_clock();
 
             let from_begin_to_audio = get_seconds_elapsed(flip_wall_clock,
                                                           get_wall_clock(),
@@ -1538,7 +1779,9 @@ pub fn winmain() {
             } else {
                 sound_is_valid = false;
             }
+            timing.sound = get_seconds_elapsed(audio_counter, get_wall_clock(), counter_frequency);
 
+            let sleep_counter = get_wall_clock();
             let mut seconds_elapsed_for_work = get_seconds_elapsed(last_counter,
                                                                    get_wall_clock(),
                                                                    counter_frequency);
@@ -1559,10 +1802,12 @@ pub fn winmain() {
                                                                    counter_frequency);
                 }
             } else {
-                // TODO: missed frame time we have to put out a log
+                timing.missed = true;
             }
 
             let end_counter = get_wall_clock();
+            timing.sleep = get_seconds_elapsed(sleep_counter, end_counter, counter_frequency);
+            timing.total = get_seconds_elapsed(last_counter, end_counter, counter_frequency);
 
             let (width, height) = get_client_dimensions(window.handle).unwrap();
 
@@ -1578,6 +1823,7 @@ pub fn winmain() {
             }
 
             flip_wall_clock = get_wall_clock();
+            timing.present = get_seconds_elapsed(end_counter, flip_wall_clock, counter_frequency);
 
             if cfg!(not(ndebug)) {
                 let mut play_cursor: DWORD = 0;
@@ -1610,10 +1856,14 @@ pub fn winmain() {
                      fps,
                      mc_per_second);
 
+            // The game sees the frames up to the previous one
+            game_memory.frame_stats.push(timing);
             mem::swap(new_input, old_input);
 
             last_counter = end_counter;
             last_cycles = intrinsics::__rdtsc();
         }
     }
+
+    game_memory.frame_stats.print_report();
 }
@@ -0,0 +1,166 @@
+// Work queues handed to the game
+//
+// Each queue owns a set of worker threads that take entries in the order
+// they were added. The game adds entries through
+// GameMemory::platform_add_work_entry and waits for a queue to run dry with
+// platform_complete_all_work, the calling thread helps out with the
+// remaining entries while it waits.
+//
+// Queues and their threads are never torn down, they live until the process
+// exits. An entry that panics still counts as finished, and the worker that
+// ran it keeps going.
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use common::{PlatformWorkQueue, ThreadContext, WorkQueueCallbackT};
+
+struct WorkEntry {
+    callback: WorkQueueCallbackT,
+    data: *mut u8,
+}
+
+// The game is responsible for data being usable from the worker threads
+unsafe impl Send for WorkEntry {}
+
+struct QueueState {
+    entries: VecDeque<WorkEntry>,
+    // Added but not finished yet, includes the entries being worked on
+    pending: usize,
+}
+
+pub struct WorkQueue {
+    state: Mutex<QueueState>,
+    work_available: Condvar,
+    all_done: Condvar,
+}
+
+// Finishes the entry on drop, so a panicking callback doesn't leave
+// complete_all waiting forever
+struct FinishGuard<'a> {
+    queue: &'a WorkQueue,
+}
+
+impl<'a> Drop for FinishGuard<'a> {
+    fn drop(&mut self) {
+        self.queue.finish_entry();
+    }
+}
+
+impl WorkQueue {
+    fn add(&self, entry: WorkEntry) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.push_back(entry);
+        state.pending += 1;
+        self.work_available.notify_one();
+    }
+
+    fn finish_entry(&self) {
+        // Nothing panics while holding the lock, but better not to panic
+        // again in here while unwinding
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.pending -= 1;
+        if state.pending == 0 {
+            self.all_done.notify_all();
+        }
+    }
+
+    fn worker(&self, context: ThreadContext) {
+        loop {
+            let entry = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if let Some(entry) = state.entries.pop_front() {
+                        break entry;
+                    }
+                    state = self.work_available.wait(state).unwrap();
+                }
+            };
+
+            // The panic already got reported by the hook
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| self.run(&context, entry)));
+        }
+    }
+
+    fn run(&self, context: &ThreadContext, entry: WorkEntry) {
+        let _guard = FinishGuard { queue: self };
+        (entry.callback)(context, entry.data);
+    }
+
+    fn complete_all(&self, context: &ThreadContext) {
+        loop {
+            let entry = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if let Some(entry) = state.entries.pop_front() {
+                        break Some(entry);
+                    }
+                    if state.pending == 0 {
+                        break None;
+                    }
+                    state = self.all_done.wait(state).unwrap();
+                }
+            };
+
+            match entry {
+                Some(entry) => self.run(context, entry),
+                None => return,
+            }
+        }
+    }
+}
+
+// Worker threads get the thread indices first_thread_index and up, index 0
+// is the main thread. Each of them calls thread_setup first if there is one.
+pub fn create_work_queue(thread_count: u32,
+                         first_thread_index: u32,
+                         thread_setup: Option<fn()>)
+                         -> *mut PlatformWorkQueue {
+    let queue: &'static WorkQueue = unsafe {
+        // Allocation implicitly freed at the end of the execution
+        &*Box::into_raw(Box::new(WorkQueue {
+            state: Mutex::new(QueueState {
+                entries: VecDeque::new(),
+                pending: 0,
+            }),
+            work_available: Condvar::new(),
+            all_done: Condvar::new(),
+        }))
+    };
+
+    for thread_index in first_thread_index..first_thread_index + thread_count {
+        let result = thread::Builder::new()
+                         .name(format!("worker {}", thread_index))
+                         .spawn(move || {
+                             if let Some(setup) = thread_setup {
+                                 setup();
+                             }
+                             queue.worker(ThreadContext { index: thread_index })
+                         });
+        if let Err(error) = result {
+            println!("Worker thread {} could not be started! ({})", thread_index, error);
+        }
+    }
+
+    queue as *const WorkQueue as *mut PlatformWorkQueue
+}
+
+pub fn platform_add_work_entry(queue: *mut PlatformWorkQueue,
+                               callback: WorkQueueCallbackT,
+                               data: *mut u8) {
+    let queue = unsafe { &*(queue as *const WorkQueue) };
+    queue.add(WorkEntry {
+        callback: callback,
+        data: data,
+    });
+}
+
+pub fn platform_complete_all_work(context: &ThreadContext, queue: *mut PlatformWorkQueue) {
+    let queue = unsafe { &*(queue as *const WorkQueue) };
+    queue.complete_all(context);
+}